@@ -1,22 +1,55 @@
 // SPDX-License-Identifier: AGPL-3.0
 
 extern crate solo2;
-use crate::config::Config;
+use crate::calculate::Calculation;
+use crate::catalogue::{self, Release};
+use crate::client_pin::{self, PinStatus};
+use crate::clock::Clock;
+use crate::config::{Config, TransportPreference};
+use crate::ctap2;
+use crate::doctor;
+use crate::error::{self, Error};
+use crate::firmware::{self, Stage};
+use crate::hotplug;
+use crate::passkeys::Passkeys;
+use crate::reset;
+use crate::self_test;
+use crate::usb::Connection;
+use crate::worker::{self, Command, Operation, Reply};
 use cosmic::cosmic_config::{self, CosmicConfigEntry};
 use cosmic::iced::{Alignment, Length, Subscription};
 use cosmic::theme;
 use cosmic::widget::{self, icon, nav_bar};
 use cosmic::{iced_futures, prelude::*};
 use futures_util::SinkExt;
-use solo2::apps::Admin;
-use solo2::apps::{Oath, oath};
-use solo2::{Select, UuidSelectable};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
 use std::time::Duration;
-use std::time::SystemTime;
+
+/// How long a TOTP code is valid for, in seconds
+pub const TOTP_PERIOD: u64 = 30;
+/// The most codes the resync dialog will generate at once
+const MAX_RESYNC_CODES: u64 = 10;
+/// Transport preferences in the order the admin page offers them
+const TRANSPORTS: [(TransportPreference, &str); 3] = [
+    (TransportPreference::Auto, "Automatic"),
+    (TransportPreference::Pcsc, "PC/SC only"),
+    (TransportPreference::Ctaphid, "CTAPHID only"),
+];
 
 // const REPOSITORY: &str = env!("CARGO_PKG_REPOSITORY");
 // const APP_ICON: &[u8] = include_bytes!("../svg/copy.svg"); // TODO: Add icon
 
+/// Options given on the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Flags {
+    /// Use simulated devices instead of real ones
+    pub demo: bool,
+    /// Unix time to freeze the clock at instead of following the system clock
+    pub time: Option<u64>,
+}
+
 /// The application model stores app-specific state used to describe its interface and
 /// drive its logic.
 pub struct AppModel {
@@ -32,10 +65,32 @@ pub struct AppModel {
     // key_binds: HashMap<menu::KeyBind, MenuAction>,
     /// Configuration data that persists between application runs.
     config: Config,
-    /// List of TOTP codes and their respective labels
-    totp_list: Vec<(String, String)>,
-    /// The Solo2 device we are conected to
-    solo2: Option<solo2::Solo2>,
+    /// Handle for writing configuration changes, None if the config could not be opened
+    config_handler: Option<cosmic_config::Config>,
+    /// The credentials on the device and their current codes
+    totp_list: Vec<worker::CredentialCode>,
+    /// Whether the codes in totp_list still came from a connected device
+    codes_valid: bool,
+    /// The last error from talking to the device, shown in a banner until dismissed
+    error: Option<Error>,
+    /// The message that retries whatever caused the error, if it can be retried
+    retry: Option<Message>,
+    /// Whether the app talks to simulated devices instead of real ones
+    demo: bool,
+    /// Unix time the clock is frozen at, if it was given on the command line
+    fixed_time: Option<u64>,
+    /// Sends commands to the worker that owns the device, None until the worker has started
+    worker: Option<mpsc::Sender<Command>>,
+    /// Operations the worker hasn't finished yet, and how many times each was sent
+    pending: BTreeMap<Operation, usize>,
+    /// UUIDs of all connected Solo2 devices
+    device_uuids: Vec<String>,
+    /// Names of all connected Solo2 devices for the device switcher
+    device_names: Vec<String>,
+    /// The current content of the nickname input on the admin page
+    nickname_input: String,
+    /// The current content of the clock offset input on the admin page
+    clock_offset_input: String,
     /// Whether to show the widget for adding a totp code or the add button
     adding_totp: bool,
     /// The current content of the label input for the add totp widget
@@ -44,11 +99,109 @@ pub struct AppModel {
     secret_input: String,
     /// If the input secret was not 16 characters (to present an error message)
     invalid_totp_code_length: bool,
+    /// Whether the credential being added is an HOTP (counter based) credential
+    adding_hotp: bool,
     /// The TOTP we asking to confirm deletion of, "" if none
     deleting_totp: Option<String>,
+    /// The current content of the code input on the verify page
+    verify_input: String,
+    /// Credentials that produced the verified code and the window they matched in, None if nothing was verified yet
+    verify_matches: Option<Vec<(String, i64)>>,
+    /// The HOTP credential we are resynchronising, None if none
+    resyncing_hotp: Option<String>,
+    /// The current content of the code count input for the resync dialog
+    resync_count_input: String,
+    /// Codes generated for the HOTP credential being resynchronised
+    resync_codes: Vec<String>,
+    /// Labels of the credentials that can be picked on the calculate page
+    calculate_labels: Vec<String>,
+    /// Index into calculate_labels of the picked credential
+    calculate_label: Option<usize>,
+    /// The current content of the challenge input on the calculate page
+    calculate_challenge: String,
+    /// Whether the challenge is hex encoded instead of text
+    calculate_hex: bool,
+    /// The result of the last calculation, or why it failed
+    calculate_result: Option<Result<Calculation, String>>,
     uuid: String,
     version: String,
     locked: bool,
+    /// The transport the selected device is talked to over
+    transport: String,
+    /// Why the selected device isn't talked to over the preferred transport
+    transport_note: Option<String>,
+    /// Every transport the selected device can be talked to over
+    transports: String,
+    /// How the selected device is plugged in
+    connection: Connection,
+    /// Names of the applets the selected device lets us select
+    applets: Vec<&'static str>,
+    /// Why PC/SC can't be used, when no devices were found
+    pcsc_problem: Option<String>,
+    /// Names of the transport preferences for the dropdown on the admin page
+    transport_names: Vec<&'static str>,
+    /// What the last run of the diagnostics found
+    diagnostics: Vec<doctor::Finding>,
+    /// Whether the diagnostics are running
+    diagnosing: bool,
+    /// The current content of the firmware file input on the admin page
+    firmware_path_input: String,
+    /// The firmware file that was read and checked, or why it couldn't be
+    firmware_file: Option<Result<Arc<[u8]>, String>>,
+    /// Whether to ask for confirmation before installing the firmware file
+    confirming_firmware: bool,
+    /// How far the running firmware update has got
+    firmware_stage: Option<Stage>,
+    /// Set to stop the running firmware update while that's still safe
+    firmware_cancel: Arc<AtomicBool>,
+    /// What happened in the last firmware update
+    firmware_result: Option<Result<String, String>>,
+    /// UUIDs of devices stuck in their bootloader, which need firmware flashed to work again
+    recovery_uuids: Vec<String>,
+    /// Whether the running firmware update is recovering a device stuck in its bootloader
+    recovering: bool,
+    /// Whether to ask for confirmation before rebooting, and whether the reboot is into the bootloader
+    confirming_reboot: Option<bool>,
+    /// The newest release in the firmware feed, or why the feed couldn't be read
+    latest_release: Option<Result<Option<Release>, String>>,
+    /// Whether the firmware feed is being read
+    checking_releases: bool,
+    /// The current content of the firmware feed input on the admin page
+    firmware_feed_input: String,
+    /// Whether the device waits for the user to touch it
+    touch_needed: bool,
+    /// Whether the selected device has a FIDO2 PIN, or why that couldn't be found out
+    pin_status: Option<Result<PinStatus, String>>,
+    /// What the selected device supports, or why that couldn't be found out
+    fido_info: Option<Result<ctap2::Info, String>>,
+    /// The current content of the current PIN input on the FIDO2 page
+    current_pin_input: String,
+    /// The current content of the new PIN input on the FIDO2 page
+    new_pin_input: String,
+    /// The current content of the PIN confirmation input on the FIDO2 page
+    confirm_pin_input: String,
+    /// What happened when the PIN was last set or changed
+    pin_result: Option<Result<String, String>>,
+    /// The current content of the PIN input for listing passkeys
+    passkey_pin_input: String,
+    /// The passkeys on the selected device, or why they couldn't be listed
+    passkeys: Option<Result<Passkeys, String>>,
+    /// Credential ID of the passkey we are asking to confirm deletion of
+    deleting_passkey: Option<Vec<u8>>,
+    /// What happened when a passkey was last deleted
+    passkey_result: Option<Result<String, String>>,
+    /// The current content of the PIN input for the self-test, on keys with a PIN
+    self_test_pin_input: String,
+    /// What the last self-test found, or the request that failed
+    self_test: Option<Result<self_test::Report, String>>,
+    /// Whether to ask for confirmation before resetting FIDO2
+    confirming_reset: bool,
+    /// How far the running FIDO2 reset has got
+    reset_stage: Option<reset::Stage>,
+    /// Set to stop the running FIDO2 reset while the key is still waiting to be replugged
+    reset_cancel: Arc<AtomicBool>,
+    /// What happened in the last FIDO2 reset
+    reset_result: Option<Result<String, String>>,
 }
 
 /// Messages emitted by the application and its widgets.
@@ -66,12 +219,132 @@ pub enum Message {
     CopyTOTP(String),
     // Wink the solo2 device's LED
     Wink,
+    // Ask whether to reboot the device, into its bootloader if bool is set
+    PromptReboot(bool),
+    // Don't reboot the device after all
+    CancelReboot,
+    // Reboot the device, into its bootloader if bool is set
+    Reboot(bool),
+    // Restart the device in recovery mode with UUID String into its firmware
+    LeaveBootloader(String),
     AddTOTPButton,
     CancelAddTOTP,
     AddTOTPCode,
     UpdateLabelInput(String),
     UpdateSecretInput(String),
+    // Toggle whether the credential being added is HOTP
+    ToggleHOTP(bool),
+    // Open the resync dialog for the HOTP credential with label String
+    PromptResyncHOTP(String),
+    // Close the resync dialog
+    CancelResyncHOTP,
+    // Update the number of codes to generate in the resync dialog
+    UpdateResyncCountInput(String),
+    // Generate consecutive codes for the HOTP credential being resynchronised
+    GenerateResyncCodes,
+    // Pick the credential to calculate with on the calculate page
+    SelectCalculateLabel(usize),
+    // Update the challenge typed into the calculate page
+    UpdateCalculateChallenge(String),
+    // Toggle whether the challenge is hex encoded
+    ToggleCalculateHex(bool),
+    // Send the challenge to the device
+    Calculate,
+    // Update the code typed into the verify page
+    UpdateVerifyInput(String),
+    // Check the typed code against every credential on the device
+    VerifyCode,
     UpdateConfig(Config),
+    // Switch to the device at index usize of the device list
+    SelectDevice(usize),
+    // Update the nickname typed into the admin page
+    UpdateNicknameInput(String),
+    // Save the typed nickname for the selected device
+    SaveNickname,
+    // Update the clock offset typed into the admin page
+    UpdateClockOffsetInput(String),
+    // Save the typed clock offset and recalculate codes with it
+    SaveClockOffset,
+    // Prefer the transport at index usize of the transport list
+    SelectTransport(usize),
+    // Check the system for common problems with finding devices
+    RunDiagnostics,
+    // The diagnostics finished with these findings
+    Diagnosed(Vec<doctor::Finding>),
+    // Copy a fix from the diagnostics page to the clipboard
+    CopyFix(String),
+    // Copy everything the admin page knows about the device to the clipboard
+    CopyDeviceInfo,
+    // Update the firmware file path typed into the admin page
+    UpdateFirmwarePathInput(String),
+    // Read and check the typed firmware file
+    LoadFirmware,
+    // The firmware file was read and checked, or couldn't be
+    FirmwareLoaded(Result<Arc<[u8]>, String>),
+    // Ask whether to install the loaded firmware file
+    PromptInstallFirmware,
+    // Don't install the loaded firmware file after all
+    CancelInstallFirmware,
+    // Install the loaded firmware file on the selected device
+    InstallFirmware,
+    // Stop the running firmware update while the old firmware is still in place
+    CancelFirmwareUpdate,
+    // Install the loaded firmware file on the device in recovery mode with UUID String
+    RecoverFirmware(String),
+    // Read the firmware feed for new releases
+    CheckReleases,
+    // The firmware feed was read, or couldn't be
+    ReleasesChecked(Result<Option<Release>, String>),
+    // Update the firmware feed typed into the admin page
+    UpdateFirmwareFeedInput(String),
+    // Read the firmware feed when the app starts if true
+    ToggleReleaseCheckOnStartup(bool),
+    // Save the typed firmware feed and read it
+    SaveFirmwareFeed,
+    // Open a web page in the browser
+    OpenUrl(String),
+    // Read the FIDO2 state of the selected device: its PIN and what it supports
+    LoadFido,
+    // The current PIN input changed
+    UpdateCurrentPinInput(String),
+    // The new PIN input changed
+    UpdateNewPinInput(String),
+    // The PIN confirmation input changed
+    UpdateConfirmPinInput(String),
+    // Set or change the FIDO2 PIN to the typed one
+    SavePin,
+    // The PIN input for listing passkeys changed
+    UpdatePasskeyPinInput(String),
+    // List the passkeys on the selected device with the typed PIN
+    LoadPasskeys,
+    // Ask to confirm deleting the passkey with this credential ID
+    PromptDeletePasskey(Vec<u8>),
+    // Don't delete the passkey after all
+    CancelDeletePasskey,
+    // Delete the passkey with this credential ID
+    DeletePasskey(Vec<u8>),
+    // The PIN input for the self-test changed
+    UpdateSelfTestPinInput(String),
+    // Register with the selected device and sign in, to see whether FIDO2 works
+    RunSelfTest,
+    // Ask to confirm resetting FIDO2
+    PromptResetFido,
+    // Don't reset FIDO2 after all
+    CancelResetFido,
+    // Start the FIDO2 reset, which waits for the key to be replugged
+    StartResetFido,
+    // Stop the running FIDO2 reset before it's sent
+    CancelReset,
+    // A device was plugged in or removed
+    Hotplug(hotplug::Event),
+    // Look for devices and reload everything from the selected one
+    RefreshDevices,
+    // The device worker started or finished an operation
+    Worker(worker::Event),
+    // Hide the error banner
+    DismissError,
+    // Hide the error banner and try again
+    RetryError,
 }
 
 /// Create a COSMIC application from the app model
@@ -80,7 +353,7 @@ impl cosmic::Application for AppModel {
     type Executor = cosmic::executor::Default;
 
     /// Data that your application receives to its init method.
-    type Flags = ();
+    type Flags = Flags;
 
     /// Messages which the application and its widgets will emit.
     type Message = Message;
@@ -97,11 +370,8 @@ impl cosmic::Application for AppModel {
     }
 
     /// Initializes the application with any given flags and startup commands.
-    fn init(
-        core: cosmic::Core,
-        _flags: Self::Flags,
-    ) -> (Self, Task<cosmic::Action<Self::Message>>) {
-        // Create a nav bar with three page items.
+    fn init(core: cosmic::Core, flags: Self::Flags) -> (Self, Task<cosmic::Action<Self::Message>>) {
+        // Create a nav bar with six page items.
         let mut nav = nav_bar::Model::default();
 
         nav.insert()
@@ -114,23 +384,44 @@ impl cosmic::Application for AppModel {
             .text("Admin")
             .data::<Page>(Page::Admin)
             .icon(icon::from_name("applications-system-symbolic"));
-        let mut solo2 = AppModel::get_device();
-        let mut totp_list: Vec<(String, String)> = vec![];
-        let mut uuid = "".to_string();
-        let mut version = "".to_string();
-        let mut locked = false;
-        if solo2.is_some() {
-            totp_list = AppModel::get_device_info(solo2.as_mut().unwrap());
 
-            let mut admin_app =
-                Admin::select(solo2.as_mut().unwrap()).expect("Could not enter admin app:");
+        nav.insert()
+            .text("Verify")
+            .data::<Page>(Page::Verify)
+            .icon(icon::from_name("emblem-ok-symbolic"));
+
+        nav.insert()
+            .text("Calculate")
+            .data::<Page>(Page::Calculate)
+            .icon(icon::from_name("accessories-calculator-symbolic"));
 
-            locked = admin_app
-                .locked()
-                .expect("Could not find out if device was locked:");
-            uuid = solo2.as_ref().unwrap().uuid().simple().to_string();
-            version = solo2.as_ref().unwrap().version().to_semver();
-        }
+        nav.insert()
+            .text("FIDO2")
+            .data::<Page>(Page::Fido)
+            .icon(icon::from_name("dialog-password-symbolic"));
+
+        nav.insert()
+            .text("Diagnostics")
+            .data::<Page>(Page::Diagnostics)
+            .icon(icon::from_name("dialog-question-symbolic"));
+        // Optional configuration file for an application.
+        // Demo mode keeps its own settings in memory so it can't mix simulated devices into the real config.
+        let config_handler = cosmic_config::Config::new(Self::APP_ID, Config::VERSION)
+            .ok()
+            .filter(|_| !flags.demo);
+        let config = config_handler
+            .as_ref()
+            .map(|context| match Config::get_entry(context) {
+                Ok(config) => config,
+                Err((_errors, config)) => {
+                    // for why in errors {
+                    //     tracing::error!(%why, "error loading app config");
+                    // }
+
+                    config
+                }
+            })
+            .unwrap_or_default();
 
         // Create the about widget
         // let about = About::default()
@@ -145,35 +436,87 @@ impl cosmic::Application for AppModel {
             core,
             // context_page: ContextPage::default(),
             // about,
-            totp_list,
-            solo2,
-            uuid,
-            version,
-            locked,
+            totp_list: vec![],
+            codes_valid: false,
+            error: None,
+            retry: None,
+            demo: flags.demo,
+            fixed_time: flags.time,
+            worker: None,
+            pending: BTreeMap::new(),
+            device_uuids: vec![],
+            device_names: vec![],
+            nickname_input: "".to_string(),
+            clock_offset_input: config.clock_offset.to_string(),
+            uuid: "".to_string(),
+            version: "".to_string(),
+            locked: false,
+            transport: "".to_string(),
+            transport_note: None,
+            transports: "".to_string(),
+            connection: Connection::default(),
+            applets: vec![],
+            pcsc_problem: None,
+            transport_names: TRANSPORTS.iter().map(|(_, name)| *name).collect(),
+            diagnostics: vec![],
+            diagnosing: false,
+            firmware_path_input: "".to_string(),
+            firmware_file: None,
+            confirming_firmware: false,
+            firmware_stage: None,
+            firmware_cancel: Arc::new(AtomicBool::new(false)),
+            firmware_result: None,
+            recovery_uuids: vec![],
+            recovering: false,
+            confirming_reboot: None,
+            latest_release: None,
+            checking_releases: false,
+            firmware_feed_input: config.firmware_feed.clone(),
+            touch_needed: false,
+            pin_status: None,
+            fido_info: None,
+            current_pin_input: "".to_string(),
+            new_pin_input: "".to_string(),
+            confirm_pin_input: "".to_string(),
+            pin_result: None,
+            passkey_pin_input: "".to_string(),
+            passkeys: None,
+            deleting_passkey: None,
+            passkey_result: None,
+            self_test_pin_input: "".to_string(),
+            self_test: None,
+            confirming_reset: false,
+            reset_stage: None,
+            reset_cancel: Arc::new(AtomicBool::new(false)),
+            reset_result: None,
             adding_totp: false,
             label_input: "".to_string(),
             secret_input: "".to_string(),
             deleting_totp: None,
+            verify_input: "".to_string(),
+            verify_matches: None,
+            adding_hotp: false,
+            resyncing_hotp: None,
+            resync_count_input: "".to_string(),
+            resync_codes: vec![],
+            calculate_labels: vec![],
+            calculate_label: None,
+            calculate_challenge: "".to_string(),
+            calculate_hex: false,
+            calculate_result: None,
             invalid_totp_code_length: false,
             nav,
             // key_binds: HashMap::new(),
-            // Optional configuration file for an application.
-            config: cosmic_config::Config::new(Self::APP_ID, Config::VERSION)
-                .map(|context| match Config::get_entry(&context) {
-                    Ok(config) => config,
-                    Err((_errors, config)) => {
-                        // for why in errors {
-                        //     tracing::error!(%why, "error loading app config");
-                        // }
-
-                        config
-                    }
-                })
-                .unwrap_or_default(),
+            config,
+            config_handler,
         };
 
-        // Create a startup command that sets the window title.
-        let command = app.update_title();
+        // Create a startup command that sets the window title, and looks for new firmware
+        // unless that's turned off. Demo mode has no real keys to compare releases with.
+        let mut command = app.update_title();
+        if !app.demo && !app.config.skip_release_check_on_startup {
+            command = Task::batch([command, app.update(Message::CheckReleases)]);
+        }
 
         (app, command)
     }
@@ -191,6 +534,22 @@ impl cosmic::Application for AppModel {
     //     vec![menu_bar.into()]
     // }
 
+    /// Elements to pack at the end of the header bar.
+    fn header_end(&self) -> Vec<Element<'_, Self::Message>> {
+        // Only offer to switch devices when there is something to switch to
+        if self.device_names.len() < 2 {
+            return vec![];
+        }
+        vec![
+            widget::dropdown(
+                &self.device_names,
+                self.selected_device(),
+                Message::SelectDevice,
+            )
+            .into(),
+        ]
+    }
+
     /// Enables the COSMIC application to create a nav bar with this model.
     fn nav_model(&self) -> Option<&nav_bar::Model> {
         Some(&self.nav)
@@ -216,12 +575,542 @@ impl cosmic::Application for AppModel {
     /// Application events will be processed through the view. Any messages emitted by
     /// events received by widgets will be passed to the update method.
     fn view(&self) -> Element<'_, Self::Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let page = if self.touch_needed {
+            widget::column::with_capacity(2)
+                .push(
+                    widget::container(widget::text::title4(
+                        "Touch your key to continue. It blinks while it waits.",
+                    ))
+                    .padding(padding)
+                    .width(Length::Fill)
+                    .class(theme::Container::Card),
+                )
+                .push(self.page_view())
+                .spacing(padding)
+                .into()
+        } else {
+            self.page_view()
+        };
+        let Some(error) = &self.error else {
+            return page;
+        };
+
+        let mut actions = widget::row::with_capacity(2).spacing(padding);
+        if self.retry.is_some() {
+            actions = actions.push(widget::button::text("Retry").on_press(Message::RetryError));
+        }
+        actions = actions.push(widget::button::text("Dismiss").on_press(Message::DismissError));
+        let banner: cosmic::Element<Message> = widget::container(
+            widget::row::with_capacity(2)
+                .push(widget::text(error.to_string()).width(Length::Fill))
+                .push(actions)
+                .spacing(padding)
+                .align_y(Alignment::Center),
+        )
+        .padding(padding)
+        .width(Length::Fill)
+        .class(theme::Container::Card)
+        .into();
+
+        widget::column::with_capacity(2)
+            .push(banner)
+            .push(page)
+            .spacing(padding)
+            .into()
+    }
+
+    /// Register subscriptions for this application.
+    ///
+    /// Subscriptions are long-running async tasks running in the background which
+    /// emit messages to the application through a channel. They can be dynamically
+    /// stopped and started conditionally based on application state, or persist
+    /// indefinitely.
+    fn subscription(&self) -> Subscription<Self::Message> {
+        // Add subscriptions which are always active.
+        let mut subscriptions = vec![
+            // Watch for application configuration changes.
+            self.core()
+                .watch_config::<Config>(Self::APP_ID)
+                .map(|update| {
+                    // for why in update.errors {
+                    //     tracing::error!(?why, "app config error");
+                    // }
+
+                    Message::UpdateConfig(update.config)
+                }),
+        ];
+
+        // Run the worker that talks to the device.
+        subscriptions.push(Subscription::run_with(self.demo, worker::stream).map(Message::Worker));
+
+        // Watch for devices being plugged in or removed.
+        subscriptions.push(Subscription::run(hotplug::stream).map(Message::Hotplug));
+
+        let active_page: &Page = self.nav.active_data().unwrap();
+
+        // Conditionally enables a timer that emits a message every second.
+        if *active_page == Page::Oath {
+            subscriptions.push(Subscription::run(|| {
+                iced_futures::stream::channel(1, |mut emitter| async move {
+                    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+                    loop {
+                        interval.tick().await;
+                        _ = emitter.send(Message::RefreshTOTPLifespan).await;
+                    }
+                })
+            }));
+        }
+
+        Subscription::batch(subscriptions)
+    }
+
+    /// Handles messages emitted by the application and its widgets.
+    ///
+    /// Tasks may be returned for asynchronous execution of code in the background
+    /// on the application's async runtime.
+    fn update(&mut self, message: Self::Message) -> Task<cosmic::Action<Self::Message>> {
+        let mut task: Option<cosmic::Task<cosmic::Action<Message>>> = None;
+        match message {
+            Message::Wink => self.send(Command::Wink),
+            Message::PromptReboot(bootloader) => self.confirming_reboot = Some(bootloader),
+            Message::CancelReboot => self.confirming_reboot = None,
+            Message::Reboot(bootloader) => {
+                self.confirming_reboot = None;
+                self.send(Command::Reboot { bootloader });
+            }
+            Message::LeaveBootloader(uuid) => self.send(Command::LeaveBootloader(uuid)),
+            Message::CopyTOTP(label) => self.send(Command::Codes {
+                label,
+                count: 1,
+                clock: self.clock(),
+            }),
+            Message::ToggleHOTP(hotp) => self.adding_hotp = hotp,
+            Message::PromptResyncHOTP(label) => {
+                self.resync_count_input = "3".to_string();
+                self.resync_codes = vec![];
+                self.resyncing_hotp = Some(label);
+            }
+            Message::CancelResyncHOTP => {
+                self.resyncing_hotp = None;
+                self.resync_codes = vec![];
+            }
+            Message::UpdateResyncCountInput(count) => self.resync_count_input = count,
+            Message::GenerateResyncCodes => {
+                if let (Some(label), Ok(count)) = (
+                    self.resyncing_hotp.clone(),
+                    self.resync_count_input.trim().parse::<u64>(),
+                ) {
+                    self.resync_codes = vec![];
+                    self.send(Command::Codes {
+                        label,
+                        count: count.min(MAX_RESYNC_CODES),
+                        clock: self.clock(),
+                    });
+                }
+            }
+            Message::RefreshTOTPLifespan => (),
+            Message::PromptDeleteTOTP(label) => self.deleting_totp = Some(label),
+            Message::CancelDeleteTOTP => self.deleting_totp = None,
+            Message::DeleteTOTP(label) => self.send(Command::Delete(label)),
+            Message::UpdateLabelInput(label) => self.label_input = label,
+            Message::UpdateSecretInput(secret) => self.secret_input = secret,
+            Message::CancelAddTOTP => {
+                self.adding_totp = false;
+            }
+            Message::AddTOTPCode => {
+                if self.secret_input.len() != 16 {
+                    self.invalid_totp_code_length = true;
+                } else {
+                    self.invalid_totp_code_length = false;
+                    self.send(Command::Register {
+                        label: self.label_input.clone(),
+                        secret: self.secret_input.clone(),
+                        hotp: self.adding_hotp,
+                    });
+                }
+            }
+            Message::UpdateVerifyInput(code) => {
+                // Old results don't describe the new code
+                self.verify_matches = None;
+                self.verify_input = code;
+            }
+            Message::VerifyCode => {
+                let code = self.verify_input.trim().to_string();
+                // Only verify codes that a credential could have produced
+                if Self::is_valid_code(&code) {
+                    self.send(Command::Verify {
+                        code,
+                        clock: self.clock(),
+                    });
+                }
+            }
+            Message::SelectCalculateLabel(index) => {
+                self.calculate_label = Some(index);
+                self.calculate_result = None;
+            }
+            Message::UpdateCalculateChallenge(challenge) => {
+                self.calculate_challenge = challenge;
+                self.calculate_result = None;
+            }
+            Message::ToggleCalculateHex(hex) => {
+                self.calculate_hex = hex;
+                self.calculate_result = None;
+            }
+            Message::Calculate => {
+                let label = self
+                    .calculate_label
+                    .and_then(|index| self.calculate_labels.get(index));
+                if let Some(label) = label.cloned() {
+                    let challenge = if self.calculate_hex {
+                        hex::decode(self.calculate_challenge.trim().replace(' ', ""))
+                            .map_err(|why| format!("Challenge is not valid hex: {why}"))
+                    } else {
+                        Ok(self.calculate_challenge.clone().into_bytes())
+                    };
+                    match challenge {
+                        Ok(challenge) => self.send(Command::Calculate { label, challenge }),
+                        Err(why) => self.calculate_result = Some(Err(why)),
+                    }
+                }
+            }
+            Message::SelectDevice(index) => {
+                if let Some(uuid) = self.device_uuids.get(index).cloned() {
+                    if let Some(config_handler) = &self.config_handler {
+                        if let Err(why) = self.config.set_last_device(config_handler, uuid) {
+                            error::log("saving selected device", why);
+                        }
+                    } else {
+                        self.config.last_device = uuid;
+                    }
+                    // Anything typed for the previous device doesn't apply to this one
+                    self.adding_totp = false;
+                    self.deleting_totp = None;
+                    self.resyncing_hotp = None;
+                    self.verify_matches = None;
+                    self.calculate_result = None;
+                    self.pin_status = None;
+                    self.fido_info = None;
+                    self.pin_result = None;
+                    self.passkeys = None;
+                    self.deleting_passkey = None;
+                    self.passkey_result = None;
+                    self.reset_result = None;
+                    self.self_test_pin_input = "".to_string();
+                    self.self_test = None;
+                    self.update_devices();
+                }
+            }
+            Message::UpdateNicknameInput(nickname) => self.nickname_input = nickname,
+            Message::SaveNickname => {
+                let mut device_nicknames = self.config.device_nicknames.clone();
+                let nickname = self.nickname_input.trim();
+                if nickname.is_empty() {
+                    device_nicknames.remove(&self.uuid);
+                } else {
+                    device_nicknames.insert(self.uuid.clone(), nickname.to_string());
+                }
+                if let Some(config_handler) = &self.config_handler {
+                    if let Err(why) = self
+                        .config
+                        .set_device_nicknames(config_handler, device_nicknames)
+                    {
+                        error::log("saving device nickname", why);
+                    }
+                } else {
+                    self.config.device_nicknames = device_nicknames;
+                }
+                self.update_device_names();
+            }
+            Message::UpdateClockOffsetInput(offset) => self.clock_offset_input = offset,
+            Message::SaveClockOffset => {
+                let Ok(clock_offset) = self.clock_offset_input.trim().parse::<i64>() else {
+                    return Task::none();
+                };
+                if let Some(config_handler) = &self.config_handler {
+                    if let Err(why) = self.config.set_clock_offset(config_handler, clock_offset) {
+                        error::log("saving clock offset", why);
+                    }
+                } else {
+                    self.config.clock_offset = clock_offset;
+                }
+                // Codes calculated with the old offset are wrong now
+                self.update_devices();
+            }
+            Message::SelectTransport(index) => {
+                let Some((transport, _)) = TRANSPORTS.get(index).copied() else {
+                    return Task::none();
+                };
+                if let Some(config_handler) = &self.config_handler {
+                    if let Err(why) = self.config.set_transport(config_handler, transport) {
+                        error::log("saving transport preference", why);
+                    }
+                } else {
+                    self.config.transport = transport;
+                }
+                // Reopen the device over the newly preferred transport
+                self.update_devices();
+            }
+            Message::RunDiagnostics => {
+                if self.diagnosing {
+                    return Task::none();
+                }
+                self.diagnosing = true;
+                // The checks talk to udev and pcscd, which can block
+                return cosmic::task::future(async {
+                    Message::Diagnosed(
+                        tokio::task::spawn_blocking(doctor::run)
+                            .await
+                            .unwrap_or_default(),
+                    )
+                });
+            }
+            Message::Diagnosed(findings) => {
+                self.diagnosing = false;
+                self.diagnostics = findings;
+            }
+            Message::CopyFix(fix) => {
+                return cosmic::iced::clipboard::write::<cosmic::Action<Message>>(fix);
+            }
+            Message::CopyDeviceInfo => {
+                return cosmic::iced::clipboard::write::<cosmic::Action<Message>>(
+                    self.device_report(),
+                );
+            }
+            Message::UpdateFirmwarePathInput(path) => {
+                self.firmware_path_input = path;
+                self.firmware_file = None;
+            }
+            Message::LoadFirmware => {
+                let path = self.firmware_path_input.trim().to_string();
+                self.firmware_result = None;
+                return cosmic::task::future(async move {
+                    let firmware = tokio::task::spawn_blocking(move || {
+                        firmware::read(std::path::Path::new(&path))
+                            .map_err(|why| format!("{why:#}"))
+                    })
+                    .await
+                    .unwrap_or_else(|why| Err(why.to_string()));
+                    Message::FirmwareLoaded(firmware)
+                });
+            }
+            Message::FirmwareLoaded(firmware) => self.firmware_file = Some(firmware),
+            Message::PromptInstallFirmware => self.confirming_firmware = true,
+            Message::CancelInstallFirmware => self.confirming_firmware = false,
+            Message::InstallFirmware => {
+                self.confirming_firmware = false;
+                if let Some(Ok(firmware)) = self.firmware_file.clone() {
+                    self.firmware_cancel = Arc::new(AtomicBool::new(false));
+                    self.firmware_stage = Some(Stage::Rebooting);
+                    self.send(Command::UpdateFirmware {
+                        firmware,
+                        cancel: self.firmware_cancel.clone(),
+                    });
+                }
+            }
+            Message::CancelFirmwareUpdate => self.firmware_cancel.store(true, Ordering::Relaxed),
+            Message::RecoverFirmware(uuid) => {
+                if let Some(Ok(firmware)) = self.firmware_file.clone() {
+                    self.recovering = true;
+                    self.firmware_result = None;
+                    self.firmware_cancel = Arc::new(AtomicBool::new(false));
+                    self.firmware_stage = Some(Stage::Flashing(0.0));
+                    self.send(Command::RecoverFirmware { uuid, firmware });
+                }
+            }
+            Message::CheckReleases => {
+                if self.checking_releases {
+                    return Task::none();
+                }
+                self.checking_releases = true;
+                let feed = self.config.firmware_feed.clone();
+                // Reading the feed can mean waiting on the network
+                return cosmic::task::future(async move {
+                    let release = tokio::task::spawn_blocking(move || {
+                        catalogue::latest(&feed).map_err(|why| format!("{why:#}"))
+                    })
+                    .await
+                    .unwrap_or_else(|why| Err(why.to_string()));
+                    Message::ReleasesChecked(release)
+                });
+            }
+            Message::ReleasesChecked(release) => {
+                self.checking_releases = false;
+                self.latest_release = Some(release);
+            }
+            Message::UpdateFirmwareFeedInput(feed) => self.firmware_feed_input = feed,
+            Message::ToggleReleaseCheckOnStartup(check) => {
+                if let Some(config_handler) = &self.config_handler {
+                    if let Err(why) = self
+                        .config
+                        .set_skip_release_check_on_startup(config_handler, !check)
+                    {
+                        error::log("saving the startup release check", why);
+                    }
+                } else {
+                    self.config.skip_release_check_on_startup = !check;
+                }
+            }
+            Message::SaveFirmwareFeed => {
+                let feed = self.firmware_feed_input.trim().to_string();
+                if let Some(config_handler) = &self.config_handler {
+                    if let Err(why) = self.config.set_firmware_feed(config_handler, feed) {
+                        error::log("saving firmware feed", why);
+                    }
+                } else {
+                    self.config.firmware_feed = feed;
+                }
+                return self.update(Message::CheckReleases);
+            }
+            Message::OpenUrl(url) => {
+                if let Err(why) = open::that_detached(&url) {
+                    error::log(format_args!("opening {url}"), why);
+                }
+            }
+            Message::LoadFido => self.load_fido(),
+            Message::UpdateCurrentPinInput(pin) => self.current_pin_input = pin,
+            Message::UpdateNewPinInput(pin) => self.new_pin_input = pin,
+            Message::UpdateConfirmPinInput(pin) => self.confirm_pin_input = pin,
+            Message::SavePin => {
+                let Some(Ok(status)) = &self.pin_status else {
+                    return Task::none();
+                };
+                if self.new_pin_problem().is_some() {
+                    return Task::none();
+                }
+                let current = status.set.then(|| self.current_pin_input.clone());
+                self.pin_result = None;
+                self.send(Command::SetPin {
+                    current,
+                    pin: self.new_pin_input.clone(),
+                });
+            }
+            Message::UpdatePasskeyPinInput(pin) => self.passkey_pin_input = pin,
+            Message::LoadPasskeys => {
+                if !self.passkey_pin_input.is_empty() {
+                    self.passkey_result = None;
+                    let pin = std::mem::take(&mut self.passkey_pin_input);
+                    self.send(Command::ListPasskeys { pin: Some(pin) });
+                }
+            }
+            Message::PromptDeletePasskey(id) => self.deleting_passkey = Some(id),
+            Message::CancelDeletePasskey => self.deleting_passkey = None,
+            Message::DeletePasskey(id) => {
+                self.deleting_passkey = None;
+                self.passkey_result = None;
+                self.send(Command::DeletePasskey(id));
+            }
+            Message::Worker(worker::Event::Firmware(stage)) => self.firmware_stage = Some(stage),
+            Message::UpdateSelfTestPinInput(pin) => self.self_test_pin_input = pin,
+            Message::RunSelfTest => {
+                self.self_test = None;
+                let pin = std::mem::take(&mut self.self_test_pin_input);
+                self.send(Command::SelfTest {
+                    pin: (!pin.is_empty()).then_some(pin),
+                });
+            }
+            Message::PromptResetFido => self.confirming_reset = true,
+            Message::CancelResetFido => self.confirming_reset = false,
+            Message::StartResetFido => {
+                self.confirming_reset = false;
+                self.reset_result = None;
+                self.reset_cancel = Arc::new(AtomicBool::new(false));
+                self.reset_stage = Some(reset::Stage::Unplug);
+                self.send(Command::ResetFido {
+                    cancel: self.reset_cancel.clone(),
+                });
+            }
+            Message::CancelReset => self.reset_cancel.store(true, Ordering::Relaxed),
+            Message::Worker(worker::Event::Touch) => self.touch_needed = true,
+            Message::Worker(worker::Event::Reset(stage)) => self.reset_stage = Some(stage),
+            // Codes from a removed device can't be trusted, even before the device list is refreshed
+            Message::Hotplug(hotplug::Event::Removed) => self.codes_valid = false,
+            Message::Hotplug(hotplug::Event::Changed) => self.update_devices(),
+            Message::RefreshDevices => self.update_devices(),
+            Message::Worker(worker::Event::Ready(sender)) => {
+                self.worker = Some(sender);
+                self.update_devices();
+            }
+            Message::Worker(worker::Event::Done(operation, reply)) => {
+                // The same operation may have been sent again before this one finished
+                if let Some(count) = self.pending.get_mut(&operation) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.pending.remove(&operation);
+                    }
+                }
+                // The worker does one thing at a time, so whatever waited for a touch is done
+                self.touch_needed = false;
+                task = self.handle_reply(reply);
+            }
+            Message::DismissError => {
+                self.error = None;
+                self.retry = None;
+            }
+            Message::RetryError => {
+                self.error = None;
+                if let Some(retry) = self.retry.take() {
+                    return self.update(retry);
+                }
+            }
+            Message::AddTOTPButton => {
+                // Get clean input state every time
+                self.secret_input = "".to_string();
+                self.label_input = "".to_string();
+                self.adding_hotp = false;
+                self.adding_totp = true;
+            }
+
+            // Demo mode doesn't follow the real config, see init
+            Message::UpdateConfig(_) if self.demo => (),
+            Message::UpdateConfig(config) => {
+                if config.clock_offset != self.config.clock_offset {
+                    self.clock_offset_input = config.clock_offset.to_string();
+                }
+                self.config = config;
+            }
+        }
+        if task.is_some() {
+            return task.unwrap();
+        }
+        Task::none()
+    }
+
+    /// Called when a nav item is selected.
+    fn on_nav_select(&mut self, id: nav_bar::Id) -> Task<cosmic::Action<Self::Message>> {
+        // Activate the page in the model.
+        self.nav.activate(id);
+
+        // Check again every time the diagnostics are opened, since the user may have fixed something
+        if self.nav.active_data::<Page>() == Some(&Page::Diagnostics) {
+            return self.update(Message::RunDiagnostics);
+        }
+        // The PIN may have been changed with another tool in the meantime
+        if self.nav.active_data::<Page>() == Some(&Page::Fido) {
+            return self.update(Message::LoadFido);
+        }
+
+        cosmic::Task::none()
+    }
+}
+
+impl AppModel {
+    /// The content of the active page, without the error banner
+    fn page_view(&self) -> Element<'_, Message> {
         let padding: u16 = cosmic::theme::spacing().space_xs;
         let xxxl_spacing: u16 = cosmic::theme::spacing().space_xxxl;
         // let space_s = cosmic::theme::spacing().space_s;
         match self.nav.active_data::<Page>().unwrap() {
+            Page::Verify => self.verify_page(),
+            Page::Calculate => self.calculate_page(),
+            Page::Fido => self.fido_page(),
+            Page::Diagnostics => self.diagnostics_page(),
             // Admin page data
             Page::Admin => {
+                if self.uuid.is_empty() && self.is_pending(&Operation::Refresh) {
+                    return widget::text("Looking for solo2 devices...").into();
+                }
                 let uuid_text: cosmic::Element<Message> = widget::row::with_capacity(2)
                     .push(widget::text("UUID:"))
                     .push(widget::text(&self.uuid))
@@ -232,44 +1121,156 @@ impl cosmic::Application for AppModel {
                     .push(widget::text(&self.version))
                     .spacing(padding)
                     .into();
-                let locked: cosmic::Element<Message> = widget::row::with_capacity(2)
-                    .push(widget::text("Locked:"))
-                    .push(widget::text(self.locked.to_string()))
+                let locked: cosmic::Element<Message> = widget::column::with_capacity(2)
+                    .push(
+                        widget::row::with_capacity(2)
+                            .push(widget::text("Secure boot:"))
+                            .push(widget::text(Self::secure_boot(self.locked)))
+                            .spacing(padding),
+                    )
+                    .push(widget::text::caption(if self.locked {
+                        "Only firmware signed by SoloKeys can run, and the key can't be unlocked again. This is how keys are sold, and it keeps modified firmware off the key."
+                    } else {
+                        "Any firmware can be flashed, which is how development keys ship. The key can't vouch that it runs genuine firmware."
+                    }))
+                    .into();
+                let mut connection = widget::column::with_capacity(6).push(
+                    widget::row::with_capacity(2)
+                        .push(widget::text("Transports:"))
+                        .push(widget::text(&self.transports))
+                        .spacing(padding),
+                );
+                for line in self.connection.to_string().lines() {
+                    connection = connection.push(widget::text(line.to_string()));
+                }
+                let applets = if self.applets.is_empty() {
+                    "none".to_string()
+                } else {
+                    self.applets.join(", ")
+                };
+                let connection = connection
+                    .push(
+                        widget::row::with_capacity(2)
+                            .push(widget::text("Applets:"))
+                            .push(widget::text(applets))
+                            .spacing(padding),
+                    )
+                    .push(
+                        widget::button::text("Copy device information").on_press_maybe(
+                            (!self.uuid.is_empty()).then_some(Message::CopyDeviceInfo),
+                        ),
+                    )
+                    .spacing(padding);
+                let winking = self.is_pending(&Operation::Wink);
+                let rebooting = self.is_pending(&Operation::Reboot);
+                let device_actions: cosmic::Element<Message> = widget::row::with_capacity(3)
+                    .push(
+                        widget::button::text(if winking { "Winking..." } else { "Wink" })
+                            .width(Length::Shrink)
+                            .on_press_maybe((!winking).then_some(Message::Wink)),
+                    )
+                    .push(
+                        widget::button::text(if rebooting { "Rebooting..." } else { "Reboot" })
+                            .on_press_maybe((!rebooting).then_some(Message::PromptReboot(false))),
+                    )
+                    .push(
+                        widget::button::text("Boot to bootloader")
+                            .on_press_maybe((!rebooting).then_some(Message::PromptReboot(true))),
+                    )
+                    .spacing(padding)
+                    .into();
+                let nickname: cosmic::Element<Message> = widget::row::with_capacity(3)
+                    .push(widget::text("Nickname:"))
+                    .push(
+                        widget::text_input("Nickname", self.nickname_input.clone())
+                            .on_input(Message::UpdateNicknameInput)
+                            .on_submit(|_| Message::SaveNickname)
+                            .width(300),
+                    )
+                    .push(widget::button::text("Save").on_press(Message::SaveNickname))
                     .spacing(padding)
+                    .align_y(Alignment::Center)
                     .into();
-                let wink_button: cosmic::Element<Message> = widget::button::text("Wink")
-                    .width(Length::Shrink)
-                    .on_press(Message::Wink)
+                let selected_transport = TRANSPORTS
+                    .iter()
+                    .position(|(transport, _)| *transport == self.config.transport);
+                let mut transport = widget::column::with_capacity(2)
+                    .push(
+                        widget::row::with_capacity(3)
+                            .push(widget::text("Transport:"))
+                            .push(widget::text(&self.transport))
+                            .push(widget::dropdown(
+                                &self.transport_names,
+                                selected_transport,
+                                Message::SelectTransport,
+                            ))
+                            .spacing(padding)
+                            .align_y(Alignment::Center),
+                    )
+                    .spacing(padding);
+                if let Some(note) = &self.transport_note {
+                    transport = transport.push(widget::text::caption(note));
+                }
+                let valid_offset = self.clock_offset_input.trim().parse::<i64>().is_ok();
+                let clock_offset: cosmic::Element<Message> = widget::row::with_capacity(3)
+                    .push(widget::text("Clock offset (seconds):"))
+                    .push(
+                        widget::text_input("0", self.clock_offset_input.clone())
+                            .on_input(Message::UpdateClockOffsetInput)
+                            .on_submit(|_| Message::SaveClockOffset)
+                            .width(100),
+                    )
+                    .push(
+                        widget::button::text("Save")
+                            .on_press_maybe(valid_offset.then_some(Message::SaveClockOffset)),
+                    )
+                    .spacing(padding)
+                    .align_y(Alignment::Center)
                     .into();
 
-                return widget::column::with_capacity(4)
+                let page = widget::column::with_capacity(12)
+                    .push_maybe(self.recovery_section())
                     .push(version)
                     .push(uuid_text)
                     .push(locked)
-                    .push(wink_button)
-                    .spacing(padding)
-                    .into();
+                    .push(transport)
+                    .push(connection)
+                    .push(nickname)
+                    .push(clock_offset)
+                    .push(device_actions)
+                    .push(self.releases_section())
+                    .push(self.firmware_section())
+                    .spacing(padding);
+                let Some(dialog) = self.admin_dialog() else {
+                    return page.into();
+                };
+                return cosmic::iced::widget::stack![
+                    page,
+                    widget::container(dialog)
+                        .height(Length::Fill)
+                        .center(Length::Fill)
+                ]
+                .into();
             }
 
             Page::Oath => {
                 // If there aren't any solo2 devices, tell the user and return early since there won't be any codes
-                if !&self.solo2.is_some() {
-                    return widget::text("No solo2 devices.").into();
+                if self.uuid.is_empty() {
+                    if self.is_pending(&Operation::Refresh) {
+                        return widget::text("Looking for solo2 devices...").into();
+                    }
+                    return self.no_devices_view();
                 }
                 // TODO: Add message when there are no totp codes
                 let mut totp_containers: Vec<cosmic::Element<Message>> = vec![];
                 let totp_list = &self.totp_list;
 
                 // How much time a totp code has left before expiring
-                let totp_lifetime = (30
-                    - (SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .expect("System time before unix epoch somehow.")
-                        .as_secs()
-                        % 30)) as f32;
+                let totp_lifetime = self.clock().seconds_left() as f32;
 
                 // Loop over the totp info and add the label and code to a card and add the card to the totp_containers collection
-                for (label, totp_code) in totp_list.into_iter() {
+                for credential in totp_list.iter() {
+                    let label = &credential.label;
                     let delete_svg = widget::svg::Handle::from_memory(
                         include_bytes!("../svg/trash.svg").as_slice(),
                     );
@@ -287,10 +1288,19 @@ impl cosmic::Application for AppModel {
                     .center_y(Length::Shrink)
                     .width(Length::Shrink)
                     .into();
-                    let totp_label_text = widget::text::title2(label)
-                        .height(Length::Fill)
-                        .align_y(Alignment::Center)
-                        .width(Length::Shrink);
+                    let totp_label_text: cosmic::Element<Message> = match &credential.code {
+                        Err(why) if self.codes_valid => widget::column::with_capacity(2)
+                            .push(widget::text::title4(label))
+                            .push(widget::text::caption(format!("Could not calculate: {why}")))
+                            .height(Length::Fill)
+                            .width(Length::Shrink)
+                            .into(),
+                        _ => widget::text::title2(label)
+                            .height(Length::Fill)
+                            .align_y(Alignment::Center)
+                            .width(Length::Shrink)
+                            .into(),
+                    };
                     let copy_svg = widget::svg::Handle::from_memory(
                         include_bytes!("../svg/copy.svg").as_slice(),
                     );
@@ -298,27 +1308,53 @@ impl cosmic::Application for AppModel {
                         widget::button::custom(widget::svg(copy_svg).width(Length::Shrink))
                             .width(Length::Shrink)
                             .height(Length::Shrink)
-                            .on_press(Message::CopyTOTP(label.clone()))
+                            .on_press_maybe(
+                                (self.codes_valid
+                                    && credential.code.is_ok()
+                                    && !self.is_pending(&Operation::Codes(label.clone())))
+                                .then(|| Message::CopyTOTP(label.clone())),
+                            )
                             .into();
-                    let totp_code_text = widget::text::title1(totp_code)
-                        .width(Length::Shrink)
-                        .height(Length::Fill)
-                        .align_y(Alignment::Center)
-                        .align_x(Alignment::End);
-                    let totp_lifetime_stack: cosmic::Element<Message> = widget::container(
-                        cosmic::iced::widget::stack!(
-                            cosmic::widget::progress_bar(-5.0..=30.0, totp_lifetime)
+                    // HOTP counters change without the list being refreshed, so read them from the config
+                    let totp_code_text = widget::text::title1(match self.hotp_counter(label) {
+                        _ if !self.codes_valid => "------".to_string(),
+                        Some(counter) if credential.hotp => format!("#{counter}"),
+                        None if credential.hotp => "HOTP".to_string(),
+                        _ => credential
+                            .code
+                            .clone()
+                            .unwrap_or_else(|_| "------".to_string()),
+                    })
+                    .width(Length::Shrink)
+                    .height(Length::Fill)
+                    .align_y(Alignment::Center)
+                    .align_x(Alignment::End);
+                    // HOTP codes don't expire, so offer to resync them instead of showing a countdown
+                    let totp_lifetime_stack: cosmic::Element<Message> = if credential.hotp {
+                        widget::button::text("Resync")
+                            .on_press(Message::PromptResyncHOTP(label.clone()))
+                            .apply(widget::container)
+                            .center_y(Length::Fill)
+                            .into()
+                    } else {
+                        widget::container(
+                            cosmic::iced::widget::stack!(
+                                cosmic::widget::progress_bar(
+                                    -5.0..=TOTP_PERIOD as f32,
+                                    totp_lifetime
+                                )
                                 .height(Length::Fill),
-                            widget::text::title3(totp_lifetime.to_string())
-                                .center()
-                                .width(Length::Fill)
-                                .height(Length::Fill)
+                                widget::text::title3(totp_lifetime.to_string())
+                                    .center()
+                                    .width(Length::Fill)
+                                    .height(Length::Fill)
+                            )
+                            .height(Length::Fill),
                         )
-                        .height(Length::Fill),
-                    )
-                    .width(40)
-                    .height(Length::Fill)
-                    .into();
+                        .width(40)
+                        .height(Length::Fill)
+                        .into()
+                    };
                     let totp_container: cosmic::Element<Message> = cosmic::widget::Container::new(
                         widget::row::with_capacity(2)
                             .push(widget::container(
@@ -363,21 +1399,28 @@ impl cosmic::Application for AppModel {
                     }
                     let label_input = widget::text_input("Label", self.label_input.clone())
                         .on_input(Message::UpdateLabelInput);
+                    let hotp_toggler = widget::toggler(self.adding_hotp)
+                        .label("HOTP")
+                        .on_toggle(Message::ToggleHOTP);
                     let secret_input = widget::text_input("Secret", self.secret_input.clone())
                         .on_input(Message::UpdateSecretInput);
-                    let add_button = widget::button::text("Add")
-                        .on_press(Message::AddTOTPCode)
-                        .class(cosmic::theme::Button::Suggested);
+                    let registering = self.is_pending(&Operation::Register);
+                    let add_button =
+                        widget::button::text(if registering { "Adding..." } else { "Add" })
+                            .on_press_maybe((!registering).then_some(Message::AddTOTPCode))
+                            .class(cosmic::theme::Button::Suggested);
                     let cancel_button =
                         widget::button::text("Cancel").on_press(Message::CancelAddTOTP);
                     let adding_totp_widget: cosmic::Element<Message> =
                         widget::column::with_capacity(2)
                             .push(
                                 widget::container(
-                                    widget::row::with_capacity(2)
+                                    widget::row::with_capacity(3)
                                         .push(label_input)
                                         .push(secret_input)
-                                        .spacing(padding),
+                                        .push(hotp_toggler)
+                                        .spacing(padding)
+                                        .align_y(Alignment::Center),
                                 )
                                 .class(cosmic::theme::Container::Card)
                                 .padding(padding),
@@ -416,10 +1459,16 @@ impl cosmic::Application for AppModel {
                     let cancel_button: cosmic::Element<Message> = widget::button::text("Cancel")
                         .on_press(Message::CancelDeleteTOTP)
                         .into();
+                    let label = self.deleting_totp.clone().unwrap();
+                    let deleting = self.is_pending(&Operation::Delete(label.clone()));
                     let delete_button: cosmic::Element<Message> =
-                        widget::button::destructive("Delete")
-                            .on_press(Message::DeleteTOTP(self.deleting_totp.clone().unwrap()))
-                            .into();
+                        widget::button::destructive(if deleting {
+                            "Deleting..."
+                        } else {
+                            "Delete"
+                        })
+                        .on_press_maybe((!deleting).then_some(Message::DeleteTOTP(label)))
+                        .into();
 
                     dialog = cosmic::widget::dialog()
                         .title("Confirm Deletion")
@@ -427,6 +1476,8 @@ impl cosmic::Application for AppModel {
                         .primary_action(cancel_button)
                         .secondary_action(delete_button)
                         .into();
+                } else if let Some(label) = &self.resyncing_hotp {
+                    dialog = self.resync_dialog(label);
                 } else {
                     dialog = widget::text("").into()
                 }
@@ -450,181 +1501,1360 @@ impl cosmic::Application for AppModel {
         }
     }
 
-    /// Register subscriptions for this application.
-    ///
-    /// Subscriptions are long-running async tasks running in the background which
-    /// emit messages to the application through a channel. They can be dynamically
-    /// stopped and started conditionally based on application state, or persist
-    /// indefinitely.
-    fn subscription(&self) -> Subscription<Self::Message> {
-        // Add subscriptions which are always active.
-        let mut subscriptions = vec![
-            // Watch for application configuration changes.
-            self.core()
-                .watch_config::<Config>(Self::APP_ID)
-                .map(|update| {
-                    // for why in update.errors {
-                    //     tracing::error!(?why, "app config error");
-                    // }
-
-                    Message::UpdateConfig(update.config)
-                }),
-        ];
-
-        let active_page: &Page = self.nav.active_data().unwrap();
-
-        // Conditionally enables a timer that emits a message every second.
-        if *active_page == Page::Oath {
-            subscriptions.push(Subscription::run(|| {
-                iced_futures::stream::channel(1, |mut emitter| async move {
-                    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    /// Updates the header and window titles.
+    pub fn update_title(&mut self) -> Task<cosmic::Action<Message>> {
+        let mut window_title = "Solo 2 GUI".to_string();
+        if self.demo {
+            window_title += " (demo)";
+        }
 
-                    loop {
-                        interval.tick().await;
-                        _ = emitter.send(Message::RefreshTOTPLifespan).await;
-                    }
-                })
-            }));
+        if let Some(id) = self.core.main_window_id() {
+            self.set_window_title(window_title, id)
+        } else {
+            Task::none()
         }
+    }
+    /// Choosing, checking and installing a firmware file, and how the last update went
+    fn firmware_section(&self) -> cosmic::Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let updating = self.is_pending(&Operation::UpdateFirmware);
+        let path_row = widget::row::with_capacity(3)
+            .push(widget::text("Firmware file:"))
+            .push(
+                widget::text_input("/path/to/firmware.sb2", self.firmware_path_input.clone())
+                    .on_input(Message::UpdateFirmwarePathInput)
+                    .on_submit(|_| Message::LoadFirmware)
+                    .width(300),
+            )
+            .push(
+                widget::button::text("Check").on_press_maybe(
+                    (!self.firmware_path_input.trim().is_empty() && !updating)
+                        .then_some(Message::LoadFirmware),
+                ),
+            )
+            .spacing(padding)
+            .align_y(Alignment::Center);
 
-        Subscription::batch(subscriptions)
+        let mut section = widget::column::with_capacity(3)
+            .push(path_row)
+            .spacing(padding);
+        match &self.firmware_file {
+            None => (),
+            Some(Ok(firmware)) => {
+                section =
+                    section.push(
+                        widget::row::with_capacity(2)
+                            .push(widget::text(format!(
+                                "Solo 2 firmware file in SB2.1 format, {} KiB. Only its format was checked, not who made it: a locked key's bootloader refuses firmware SoloKeys didn't sign, an unlocked key's bootloader accepts any.",
+                                firmware.len() / 1024
+                            )))
+                            .push(widget::button::suggested("Install").on_press_maybe(
+                                (!updating).then_some(Message::PromptInstallFirmware),
+                            ))
+                            .spacing(padding)
+                            .align_y(Alignment::Center),
+                    );
+            }
+            Some(Err(why)) => section = section.push(widget::text(why.clone())),
+        }
+        match &self.firmware_result {
+            None => (),
+            Some(Ok(outcome)) | Some(Err(outcome)) => {
+                section = section.push(widget::text(outcome.clone()));
+            }
+        }
+        section.into()
     }
+    /// Where firmware releases come from, and a banner when the newest one is newer than the
+    /// selected device's firmware
+    fn releases_section(&self) -> cosmic::Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let feed_row = widget::row::with_capacity(3)
+            .push(widget::text("Release feed:"))
+            .push(
+                widget::text_input(catalogue::UPSTREAM_FEED, self.firmware_feed_input.clone())
+                    .on_input(Message::UpdateFirmwareFeedInput)
+                    .on_submit(|_| Message::SaveFirmwareFeed)
+                    .width(300),
+            )
+            .push(
+                widget::button::text(if self.checking_releases {
+                    "Checking..."
+                } else {
+                    "Check for updates"
+                })
+                .on_press_maybe((!self.checking_releases).then_some(Message::SaveFirmwareFeed)),
+            )
+            .spacing(padding)
+            .align_y(Alignment::Center);
+        let startup_toggler = widget::toggler(!self.config.skip_release_check_on_startup)
+            .label("Check for updates when the app starts")
+            .on_toggle(Message::ToggleReleaseCheckOnStartup);
+        let mut section = widget::column::with_capacity(3)
+            .push(feed_row)
+            .push(startup_toggler)
+            .spacing(padding);
 
-    /// Handles messages emitted by the application and its widgets.
-    ///
-    /// Tasks may be returned for asynchronous execution of code in the background
-    /// on the application's async runtime.
-    fn update(&mut self, message: Self::Message) -> Task<cosmic::Action<Self::Message>> {
-        let mut task: Option<cosmic::Task<cosmic::Action<Message>>> = None;
-        match message {
-            Message::Wink => {
-                let mut admin_app = Admin::select(self.solo2.as_mut().unwrap())
-                    .expect("Could not enter admin app:");
-                let _ = admin_app.wink();
-            }
-            Message::CopyTOTP(label) => {
-                let solo2 = self.solo2.as_mut().unwrap();
-                let mut app = Oath::select(solo2).expect("Could not enter oath app.");
-                task = Some(cosmic::iced::clipboard::write::<cosmic::Action<Message>>(
-                    app.authenticate(solo2::apps::oath::Authenticate::with_label(&label))
-                        .expect("No TOTP with label: {label}"),
-                ));
+        match &self.latest_release {
+            Some(Ok(Some(release)))
+                if !self.uuid.is_empty()
+                    && catalogue::is_newer(&release.version, &self.version) =>
+            {
+                let mut banner = widget::column::with_capacity(3)
+                    .push(widget::text::title4(format!(
+                        "Firmware {} is available. This key runs {}.",
+                        release.version, self.version
+                    )))
+                    .spacing(padding);
+                if !release.notes.is_empty() {
+                    banner = banner.push(widget::text(&release.notes));
+                }
+                if !release.url.is_empty() {
+                    banner = banner.push(
+                        widget::button::text("Open release page")
+                            .on_press(Message::OpenUrl(release.url.clone())),
+                    );
+                }
+                section = section.push(
+                    widget::container(banner)
+                        .padding(padding)
+                        .width(Length::Fill)
+                        .class(theme::Container::Card),
+                );
             }
-            Message::RefreshTOTPLifespan => (),
-            Message::PromptDeleteTOTP(label) => self.deleting_totp = Some(label),
-            Message::CancelDeleteTOTP => self.deleting_totp = None,
-            Message::DeleteTOTP(label) => {
-                let solo2 = self.solo2.as_mut().unwrap();
-                let mut app = Oath::select(solo2).expect("Could not enter oath app.");
-                app.delete(label).expect("Could not delete TOTP.");
-                // Update TOTP list to reflect the deleted entry
+            Some(Ok(Some(_))) if !self.uuid.is_empty() => {
+                section = section.push(widget::text("This key runs the newest firmware."));
+            }
+            Some(Ok(None)) => {
+                section = section.push(widget::text("The release feed has no releases."));
+            }
+            Some(Err(why)) => {
+                section = section.push(widget::text(format!("Could not check for updates: {why}")));
+            }
+            _ => (),
+        }
+        section.into()
+    }
+    /// Devices stuck in their bootloader, with a way to flash the checked firmware file to them
+    fn recovery_section(&self) -> Option<cosmic::Element<'_, Message>> {
+        if self.recovery_uuids.is_empty() {
+            return None;
+        }
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let can_flash = matches!(self.firmware_file, Some(Ok(_)))
+            && !self.is_pending(&Operation::UpdateFirmware);
+        let rebooting = self.is_pending(&Operation::Reboot);
+        let mut section = widget::column::with_capacity(self.recovery_uuids.len()).spacing(padding);
+        for uuid in &self.recovery_uuids {
+            let card = widget::column::with_capacity(3)
+                .push(widget::text::title4(format!("Recovery needed: {uuid}")))
+                .push(widget::text(
+                    "This key is in its bootloader. If a firmware update was interrupted, it can't be used until firmware is written to it again: check a firmware file below, then flash it. Otherwise, restarting it is enough.",
+                ))
+                .push(
+                    widget::row::with_capacity(2)
+                        .push(
+                            widget::button::suggested("Flash firmware").on_press_maybe(
+                                can_flash.then(|| Message::RecoverFirmware(uuid.clone())),
+                            ),
+                        )
+                        .push(
+                            widget::button::text(if rebooting { "Restarting..." } else { "Restart" })
+                                .on_press_maybe(
+                                    (!rebooting).then(|| Message::LeaveBootloader(uuid.clone())),
+                                ),
+                        )
+                        .spacing(padding),
+                )
+                .spacing(padding);
+            section = section.push(
+                widget::container(card)
+                    .padding(padding)
+                    .width(Length::Fill)
+                    .class(theme::Container::Card),
+            );
+        }
+        Some(section.into())
+    }
+    /// What the locked flag means for secure boot
+    fn secure_boot(locked: bool) -> &'static str {
+        if locked { "Locked" } else { "Unlocked" }
+    }
+    /// Everything the admin page knows about the selected device, as text for support tickets
+    fn device_report(&self) -> String {
+        format!(
+            "Solo 2 {}\nFirmware version: {}\nSecure boot: {}\nTransport: {}\nTransports: {}\n{}\nApplets: {}\n",
+            self.uuid,
+            self.version,
+            Self::secure_boot(self.locked),
+            self.transport,
+            self.transports,
+            self.connection,
+            self.applets.join(", "),
+        )
+    }
+    /// The dialog shown over the admin page, if there is one
+    fn admin_dialog(&self) -> Option<cosmic::Element<'_, Message>> {
+        if let Some(stage) = self.firmware_stage {
+            let cancelling = self.firmware_cancel.load(Ordering::Relaxed);
+            let cancel_button = widget::button::text(if cancelling {
+                "Cancelling..."
+            } else {
+                "Cancel"
+            })
+            .on_press_maybe(
+                (stage.can_cancel() && !cancelling).then_some(Message::CancelFirmwareUpdate),
+            );
+            return Some(
+                widget::dialog()
+                    .title("Updating Firmware")
+                    .body(stage.description())
+                    .control(widget::progress_bar(0.0..=1.0, stage.progress()))
+                    .primary_action(cancel_button)
+                    .into(),
+            );
+        }
+        if let Some(bootloader) = self.confirming_reboot {
+            let (title, body, action) = if bootloader {
+                (
+                    "Boot to Bootloader?",
+                    "The key restarts into its bootloader, where it can only be flashed with firmware. It shows up under recovery until it's restarted or flashed.",
+                    "Boot to bootloader",
+                )
+            } else {
+                (
+                    "Reboot?",
+                    "The key restarts and is looked for again once it's back.",
+                    "Reboot",
+                )
+            };
+            return Some(
+                widget::dialog()
+                    .title(title)
+                    .body(body)
+                    .primary_action(widget::button::text("Cancel").on_press(Message::CancelReboot))
+                    .secondary_action(
+                        widget::button::destructive(action).on_press(Message::Reboot(bootloader)),
+                    )
+                    .into(),
+            );
+        }
+        if self.confirming_firmware {
+            return Some(
+                widget::dialog()
+                    .title("Install Firmware?")
+                    .body("The key reboots into its bootloader and the new firmware is written to it. The app doesn't check who made the file: if the key is locked, its bootloader refuses firmware that SoloKeys didn't sign, but an unlocked key installs whatever it's given. Keep the key plugged in until the update finishes.")
+                    .primary_action(
+                        widget::button::suggested("Install").on_press(Message::InstallFirmware),
+                    )
+                    .secondary_action(
+                        widget::button::text("Cancel").on_press(Message::CancelInstallFirmware),
+                    )
+                    .into(),
+            );
+        }
+        None
+    }
+    /// Says there are no devices, and why PC/SC ones can't be found if that's the case
+    fn no_devices_view(&self) -> cosmic::Element<'_, Message> {
+        if !self.recovery_uuids.is_empty() {
+            return widget::text(
+                "No working solo2 devices. A key that needs recovery is listed on the admin page.",
+            )
+            .into();
+        }
+        let Some(why) = &self.pcsc_problem else {
+            return widget::text("No solo2 devices.").into();
+        };
+        widget::column::with_capacity(2)
+            .push(widget::text("No solo2 devices."))
+            .push(widget::text::caption(format!(
+                "PC/SC is unavailable ({why}), so only devices reachable over CTAPHID can be found. The diagnostics page can tell what's wrong."
+            )))
+            .spacing(cosmic::theme::spacing().space_xs)
+            .into()
+    }
+    /// Where the time for codes comes from: a frozen time from the command line, or the system
+    /// clock shifted by the configured offset
+    fn clock(&self) -> Clock {
+        match self.fixed_time {
+            Some(time) => Clock::Fixed(time),
+            None if self.config.clock_offset == 0 => Clock::System,
+            None => Clock::Offset(self.config.clock_offset),
+        }
+    }
+    /// Asks the worker for the selected device's FIDO2 PIN state and what it supports
+    fn load_fido(&mut self) {
+        self.send(Command::PinStatus);
+        self.send(Command::FidoInfo);
+    }
+    /// Asks the worker to look for devices and reload everything from the selected one
+    pub fn update_devices(&mut self) {
+        self.send(Command::Refresh {
+            preferred: self.config.last_device.clone(),
+            clock: self.clock(),
+            transport: self.config.transport,
+        });
+    }
+    /// Hands a command to the worker and marks its operation as running
+    fn send(&mut self, command: Command) {
+        let Some(worker) = &self.worker else {
+            // Commands before the worker starts are covered by the refresh it triggers
+            return;
+        };
+        *self.pending.entry(command.operation()).or_default() += 1;
+        if worker.send(command).is_err() {
+            self.pending.clear();
+            self.show_error(Error::WorkerStopped, None);
+        }
+    }
+    /// Whether the worker is still busy with an operation
+    fn is_pending(&self, operation: &Operation) -> bool {
+        self.pending.contains_key(operation)
+    }
+    /// Applies what the worker did to the model
+    fn handle_reply(&mut self, reply: Reply) -> Option<Task<cosmic::Action<Message>>> {
+        match reply {
+            Reply::Refreshed {
+                uuids,
+                device,
+                pcsc_problem,
+                recovery,
+            } => {
+                self.device_uuids = uuids;
+                self.pcsc_problem = pcsc_problem;
+                self.recovery_uuids = recovery;
+                self.update_device_names();
+                let refreshed = device.is_ok();
+                let info = match device {
+                    Ok(info) => {
+                        // A successful refresh means whatever failed before can be tried again as normal
+                        if matches!(self.retry, Some(Message::RefreshDevices)) {
+                            self.error = None;
+                            self.retry = None;
+                        }
+                        info.unwrap_or_default()
+                    }
+                    Err(why) => {
+                        self.show_error(why, Some(Message::RefreshDevices));
+                        worker::DeviceInfo::default()
+                    }
+                };
+                self.uuid = info.uuid;
+                self.version = info.version;
+                self.locked = info.locked;
+                self.transport = info.transport;
+                self.transport_note = info.transport_note;
+                self.transports = info.transports;
+                self.connection = info.connection;
+                self.applets = info.applets;
+                self.totp_list = info.totp_list;
+                // Codes are only current if the refresh worked
+                self.codes_valid = refreshed;
+                self.nickname_input = self
+                    .config
+                    .device_nicknames
+                    .get(&self.uuid)
+                    .cloned()
+                    .unwrap_or_default();
+                self.update_calculate_labels();
+                // Switching devices forgets the PIN state, so read it again for the one now shown
+                if self.pin_status.is_none()
+                    && !self.uuid.is_empty()
+                    && self.nav.active_data::<Page>() == Some(&Page::Fido)
+                {
+                    self.load_fido();
+                }
+            }
+            Reply::FirmwareUpdated(result) => {
+                self.firmware_stage = None;
+                let recovered = std::mem::take(&mut self.recovering);
+                self.firmware_result = Some(match result {
+                    Ok(version) if recovered => Ok(format!(
+                        "Recovered the key. It now runs firmware {version}."
+                    )),
+                    Ok(version) if version == self.version => Ok(format!(
+                        "The firmware was installed, but the key still reports version {version}. The file may hold the version it already had."
+                    )),
+                    Ok(version) => Ok(format!(
+                        "Updated the firmware from version {} to {version}.",
+                        self.version
+                    )),
+                    Err(why) => Err(why),
+                });
+                // The reboot dropped every connection, and codes may have changed with the firmware
+                self.update_devices();
+            }
+            Reply::Rebooted(result) => {
+                if let Err(why) = result {
+                    self.show_error(why, None);
+                }
+                // Look for the device again, wherever it ended up
                 self.update_devices();
+            }
+            Reply::Winked(result) => {
+                if let Err(why) = result {
+                    self.show_error(why, Some(Message::Wink));
+                }
+            }
+            Reply::Codes {
+                label,
+                codes,
+                error,
+            } => {
+                // Every HOTP code the device calculates moves its counter forward
+                if let Some(counter) = self.hotp_counter(&label) {
+                    self.set_hotp_counter(label.clone(), counter + codes.len() as u64);
+                }
+                if self.resyncing_hotp.as_ref() == Some(&label) {
+                    self.resync_codes.extend(codes);
+                    if let Some(why) = error {
+                        self.show_error(why, Some(Message::GenerateResyncCodes));
+                    }
+                } else if let Some(why) = error {
+                    self.show_error(why, Some(Message::CopyTOTP(label)));
+                } else if let Some(code) = codes.into_iter().next() {
+                    return Some(cosmic::iced::clipboard::write::<cosmic::Action<Message>>(
+                        code,
+                    ));
+                }
+            }
+            Reply::Deleted { label, result } => {
+                if let Err(why) = result {
+                    self.show_error(why, Some(Message::DeleteTOTP(label)));
+                    return None;
+                }
+                // Forget the counter of a deleted HOTP credential
+                if self.hotp_counter(&label).is_some() {
+                    let mut device_hotp_counters = self.config.device_hotp_counters.clone();
+                    if let Some(hotp_counters) = device_hotp_counters.get_mut(&self.uuid) {
+                        hotp_counters.remove(&label);
+                        if hotp_counters.is_empty() {
+                            device_hotp_counters.remove(&self.uuid);
+                        }
+                    }
+                    self.save_hotp_counters(device_hotp_counters);
+                }
                 // No longer prompting to delete TOTP code
                 self.deleting_totp = None;
+                // Update TOTP list to reflect the deleted entry
+                self.update_devices();
             }
-            Message::UpdateLabelInput(label) => self.label_input = label,
-            Message::UpdateSecretInput(secret) => self.secret_input = secret,
-            Message::CancelAddTOTP => {
+            Reply::Registered {
+                label,
+                hotp,
+                result,
+            } => {
+                // Keep the inputs so the user can fix them or retry
+                if let Err(why) = result {
+                    self.show_error(why, Some(Message::AddTOTPCode));
+                    return None;
+                }
+                if hotp {
+                    self.set_hotp_counter(label, 0);
+                }
+                // Clear inputs and get out of adding_totp screen
                 self.adding_totp = false;
+                self.update_devices();
             }
-            Message::AddTOTPCode => {
-                if self.secret_input.len() != 16 {
-                    self.invalid_totp_code_length = true;
-                } else {
-                    self.invalid_totp_code_length = false;
-                    let solo2 = self.solo2.as_mut().unwrap(); // Can unwrap because totp screen won't be shown if there are no devices
-                    let mut app = Oath::select(solo2).expect("Could not enter oath app.");
-
-                    app.register(
-                        solo2::apps::oath::Credential::default_totp(
-                            &self.label_input,
-                            &self.secret_input,
-                        )
-                        .expect("Could not get credential"),
-                    )
-                    .expect("Could not register TOTP code.");
-                    // Clear inputs and get out of adding_totp screen
-                    self.adding_totp = false;
-                    self.update_devices();
+            Reply::Verified(result) => match result {
+                Ok(matches) => self.verify_matches = Some(matches),
+                Err(why) => self.show_error(why, Some(Message::VerifyCode)),
+            },
+            Reply::Calculated(result) => self.calculate_result = Some(result),
+            Reply::PinStatus(status) => self.pin_status = Some(status),
+            Reply::FidoInfo(info) => self.fido_info = Some(info),
+            Reply::Passkeys(passkeys) => {
+                // A wrong PIN uses up a try
+                if passkeys.is_err() {
+                    self.send(Command::PinStatus);
                 }
+                self.passkeys = Some(passkeys);
             }
-            Message::AddTOTPButton => {
-                // Get clean input state every time
-                self.secret_input = "".to_string();
-                self.label_input = "".to_string();
-                self.adding_totp = true;
+            Reply::PasskeyDeleted(result) => match result {
+                Ok(()) => {
+                    self.passkey_result = Some(Ok("Deleted the passkey.".to_string()));
+                    self.send(Command::ListPasskeys { pin: None });
+                }
+                Err(why) => {
+                    self.passkey_result = Some(Err(format!("Could not delete the passkey: {why}")))
+                }
+            },
+            Reply::SelfTest(report) => self.self_test = Some(report),
+            Reply::FidoReset(result) => {
+                self.reset_stage = None;
+                self.reset_result = Some(result.map(|()| {
+                    "FIDO2 was reset. The key has no passkeys and no PIN now.".to_string()
+                }));
+                // The PIN, passkeys and token are all gone, and the key was replugged
+                self.pin_status = None;
+                self.passkeys = None;
+                self.passkey_result = None;
+                self.pin_result = None;
+                self.load_fido();
+                self.update_devices();
             }
-
-            Message::UpdateConfig(config) => {
-                self.config = config;
+            Reply::PinSet { changed, result } => {
+                // PINs don't stay around longer than they're needed
+                self.current_pin_input.clear();
+                self.pin_result = Some(match result {
+                    Ok(()) => {
+                        self.new_pin_input.clear();
+                        self.confirm_pin_input.clear();
+                        // A new PIN invalidates the token the passkeys were listed with
+                        self.passkeys = None;
+                        Ok(if changed { "PIN changed." } else { "PIN set." }.to_string())
+                    }
+                    Err(why) => Err(why),
+                });
+                // A wrong PIN uses up a try, and a new PIN resets them
+                self.send(Command::PinStatus);
             }
         }
-        if task.is_some() {
-            return task.unwrap();
+        None
+    }
+    /// Shows an error in the banner, with a retry button if `retry` is given
+    fn show_error(&mut self, error: Error, retry: Option<Message>) {
+        self.error = Some(error);
+        self.retry = retry;
+    }
+    /// Refreshes the names shown in the device switcher, using nicknames where there are any
+    fn update_device_names(&mut self) {
+        self.device_names = self
+            .device_uuids
+            .iter()
+            .map(|uuid| match self.config.device_nicknames.get(uuid) {
+                Some(nickname) => format!("{nickname} ({})", &uuid[..8.min(uuid.len())]),
+                None => uuid.clone(),
+            })
+            .collect();
+    }
+    /// The index of the selected device in the device list
+    fn selected_device(&self) -> Option<usize> {
+        self.device_uuids.iter().position(|uuid| *uuid == self.uuid)
+    }
+    /// Refreshes the credentials offered on the calculate page, keeping the picked one if it still exists.
+    /// HOTP credentials are left out since calculating with them would move their counters.
+    fn update_calculate_labels(&mut self) {
+        let picked = self
+            .calculate_label
+            .and_then(|index| self.calculate_labels.get(index).cloned());
+        self.calculate_labels = self
+            .totp_list
+            .iter()
+            .filter(|credential| !credential.hotp)
+            .map(|credential| credential.label.clone())
+            .collect();
+        self.calculate_label = picked.and_then(|picked| {
+            self.calculate_labels
+                .iter()
+                .position(|label| *label == picked)
+        });
+    }
+    /// How many codes the selected device has generated for an HOTP credential, if known
+    fn hotp_counter(&self, label: &str) -> Option<u64> {
+        self.config
+            .device_hotp_counters
+            .get(&self.uuid)?
+            .get(label)
+            .copied()
+    }
+    /// Records how many codes the selected device has generated for an HOTP credential
+    fn set_hotp_counter(&mut self, label: String, counter: u64) {
+        let mut device_hotp_counters = self.config.device_hotp_counters.clone();
+        device_hotp_counters
+            .entry(self.uuid.clone())
+            .or_default()
+            .insert(label, counter);
+        self.save_hotp_counters(device_hotp_counters);
+    }
+    /// Saves HOTP counters to the config, falling back to keeping them in memory if it can't be written
+    fn save_hotp_counters(
+        &mut self,
+        device_hotp_counters: BTreeMap<String, BTreeMap<String, u64>>,
+    ) {
+        let Some(config_handler) = &self.config_handler else {
+            self.config.device_hotp_counters = device_hotp_counters;
+            return;
+        };
+        if let Err(why) = self
+            .config
+            .set_device_hotp_counters(config_handler, device_hotp_counters)
+        {
+            error::log("saving HOTP counters", why);
         }
-        Task::none()
     }
+    /// Whether the string looks like an OATH code, i.e. 6 or 8 digits
+    fn is_valid_code(code: &str) -> bool {
+        (code.len() == 6 || code.len() == 8) && code.chars().all(|c| c.is_ascii_digit())
+    }
+    /// The dialog for generating consecutive codes to resynchronise an HOTP credential with a service
+    fn resync_dialog(&self, label: &str) -> cosmic::Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let generated = match self.hotp_counter(label) {
+            Some(counter) => {
+                format!("The device has generated {counter} codes for this credential.")
+            }
+            // Registered before counters were kept per device, or from another app
+            None => "It isn't known how many codes the device has generated for this credential."
+                .to_string(),
+        };
+        let count_input = widget::text_input("Number of codes", self.resync_count_input.clone())
+            .on_input(Message::UpdateResyncCountInput)
+            .on_submit(|_| Message::GenerateResyncCodes);
+        let mut codes = widget::column::with_capacity(self.resync_codes.len() + 1)
+            .push(count_input)
+            .spacing(padding);
+        for code in self.resync_codes.iter() {
+            codes = codes.push(widget::text::title3(code.clone()));
+        }
+        let close_button: cosmic::Element<Message> = widget::button::text("Close")
+            .on_press(Message::CancelResyncHOTP)
+            .into();
+        let generating = self.is_pending(&Operation::Codes(label.to_string()));
+        let generate_button: cosmic::Element<Message> = widget::button::suggested(if generating {
+            "Generating..."
+        } else {
+            "Generate"
+        })
+        .on_press_maybe((!generating).then_some(Message::GenerateResyncCodes))
+        .into();
 
-    /// Called when a nav item is selected.
-    fn on_nav_select(&mut self, id: nav_bar::Id) -> Task<cosmic::Action<Self::Message>> {
-        // Activate the page in the model.
-        self.nav.activate(id);
+        cosmic::widget::dialog()
+            .title(format!("Resync \"{label}\""))
+            .body(format!(
+                "{generated} Generate consecutive codes and submit them to the service so it can catch up with the device."
+            ))
+            .control(codes)
+            .primary_action(generate_button)
+            .secondary_action(close_button)
+            .into()
+    }
+    /// The page for sending arbitrary challenges to a credential and showing the raw HMAC
+    fn calculate_page(&self) -> Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        if self.uuid.is_empty() {
+            return widget::text("No solo2 devices.").into();
+        }
 
-        cosmic::Task::none()
+        let label_dropdown = widget::dropdown(
+            &self.calculate_labels,
+            self.calculate_label,
+            Message::SelectCalculateLabel,
+        );
+        let challenge_placeholder = if self.calculate_hex {
+            "Challenge (hex)"
+        } else {
+            "Challenge (text)"
+        };
+        let challenge_input =
+            widget::text_input(challenge_placeholder, self.calculate_challenge.clone())
+                .on_input(Message::UpdateCalculateChallenge)
+                .on_submit(|_| Message::Calculate);
+        let hex_toggler = widget::toggler(self.calculate_hex)
+            .label("Hex")
+            .on_toggle(Message::ToggleCalculateHex);
+        let calculating = self.is_pending(&Operation::Calculate);
+        let mut calculate_button = widget::button::text(if calculating {
+            "Calculating..."
+        } else {
+            "Calculate"
+        })
+        .class(theme::Button::Suggested);
+        if self.calculate_label.is_some() && !calculating {
+            calculate_button = calculate_button.on_press(Message::Calculate);
+        }
+        let input_row: cosmic::Element<Message> = widget::row::with_capacity(4)
+            .push(label_dropdown)
+            .push(challenge_input)
+            .push(hex_toggler)
+            .push(calculate_button)
+            .spacing(padding)
+            .align_y(Alignment::Center)
+            .into();
+
+        let result: cosmic::Element<Message> = match &self.calculate_result {
+            None => widget::text("").into(),
+            Some(Err(why)) => widget::text(why.clone()).into(),
+            Some(Ok(calculation)) => {
+                let local_truncation = match calculation.truncate_hmac() {
+                    Some(truncated) if truncated == calculation.truncated => {
+                        "matches the device".to_string()
+                    }
+                    Some(truncated) => format!("{truncated:08x}, differs from the device"),
+                    None => "HMAC too short to truncate".to_string(),
+                };
+                let rows = [
+                    ("HMAC:", hex::encode(&calculation.hmac)),
+                    ("Truncated:", format!("{:08x}", calculation.truncated)),
+                    ("Code:", calculation.code()),
+                    ("Local truncation:", local_truncation),
+                ];
+                let mut column = widget::column::with_capacity(rows.len()).spacing(padding);
+                for (name, value) in rows {
+                    column = column.push(
+                        widget::row::with_capacity(2)
+                            .push(widget::text(name))
+                            .push(widget::text(value))
+                            .spacing(padding),
+                    );
+                }
+                widget::container(column)
+                    .padding(padding)
+                    .width(Length::Fill)
+                    .class(theme::Container::Card)
+                    .into()
+            }
+        };
+
+        widget::column::with_capacity(3)
+            .push(widget::text(
+                "Send a challenge to a credential and show the HMAC the device calculates.",
+            ))
+            .push(input_row)
+            .push(result)
+            .spacing(padding)
+            .width(1000)
+            .apply(widget::container)
+            .width(Length::Fill)
+            .align_x(Alignment::Center)
+            .into()
     }
-}
+    /// The page showing the FIDO2 PIN's state, for setting or changing it
+    fn fido_page(&self) -> Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        if self.uuid.is_empty() {
+            return widget::text("No solo2 devices.").into();
+        }
+        let status = match &self.pin_status {
+            None => return widget::text("Reading the FIDO2 state...").into(),
+            Some(Err(why)) => {
+                return widget::column::with_capacity(2)
+                    .push(widget::text(format!(
+                        "Could not read the FIDO2 state: {why}"
+                    )))
+                    .push(widget::button::text("Try again").on_press_maybe(
+                        (!self.is_pending(&Operation::PinStatus)).then_some(Message::LoadFido),
+                    ))
+                    .spacing(padding)
+                    .into();
+            }
+            Some(Ok(status)) => status,
+        };
 
-impl AppModel {
-    /// Updates the header and window titles.
-    pub fn update_title(&mut self) -> Task<cosmic::Action<Message>> {
-        let window_title = "Solo 2 GUI".to_string();
+        let status_text = match status.retries {
+            Some(0) => "PIN: blocked. Only resetting FIDO2 makes the key usable again.".to_string(),
+            Some(1) => "PIN: set, 1 try left".to_string(),
+            Some(retries) => format!("PIN: set, {retries} tries left"),
+            None => "PIN: not set".to_string(),
+        };
+        let saving = self.is_pending(&Operation::SetPin);
+        let problem = self.new_pin_problem();
+        let can_save =
+            problem.is_none() && !saving && (!status.set || !self.current_pin_input.is_empty());
 
-        if let Some(id) = self.core.main_window_id() {
-            self.set_window_title(window_title, id)
+        let mut form = widget::column::with_capacity(5).spacing(padding);
+        if status.set {
+            form = form.push(
+                widget::secure_input("Current PIN", self.current_pin_input.clone(), None, true)
+                    .on_input(Message::UpdateCurrentPinInput),
+            );
+        }
+        form = form
+            .push(
+                widget::secure_input("New PIN", self.new_pin_input.clone(), None, true)
+                    .on_input(Message::UpdateNewPinInput),
+            )
+            .push(
+                widget::secure_input("Repeat new PIN", self.confirm_pin_input.clone(), None, true)
+                    .on_input(Message::UpdateConfirmPinInput)
+                    .on_submit(|_| Message::SavePin),
+            );
+        // Don't complain about a PIN that hasn't been typed yet
+        if let Some(problem) = problem.filter(|_| !self.new_pin_input.is_empty()) {
+            form = form.push(widget::text::caption(problem));
+        }
+        let action = match (saving, status.set) {
+            (true, _) => "Saving...",
+            (false, true) => "Change PIN",
+            (false, false) => "Set PIN",
+        };
+        form = form.push(
+            widget::button::suggested(action).on_press_maybe(can_save.then_some(Message::SavePin)),
+        );
+        match &self.pin_result {
+            None => (),
+            Some(Ok(outcome)) | Some(Err(outcome)) => {
+                form = form.push(widget::text(outcome.clone()));
+            }
+        }
+
+        let usable = status.retries != Some(0);
+        let page = widget::column::with_capacity(5)
+            .push(widget::text(
+                "The FIDO2 PIN protects passkeys and security key logins on this key. Websites that ask for it can't see it.",
+            ))
+            .push(widget::text::title4(status_text))
+            .push_maybe(usable.then_some(form))
+            .push_maybe((status.set && usable).then(|| self.passkeys_section()))
+            .push(self.self_test_section())
+            .push(self.reset_section())
+            .push(self.fido_info_section())
+            .spacing(padding)
+            .width(1000)
+            .apply(widget::container)
+            .width(Length::Fill)
+            .align_x(Alignment::Center);
+        let Some(dialog) = self.fido_dialog() else {
+            return page.into();
+        };
+        cosmic::iced::widget::stack![
+            page,
+            widget::container(dialog)
+                .height(Length::Fill)
+                .center(Length::Fill)
+        ]
+        .into()
+    }
+    /// The passkeys on the key grouped by site, once the PIN has been entered to see them
+    fn passkeys_section(&self) -> cosmic::Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let loading = self.is_pending(&Operation::ListPasskeys);
+        let unlock_row = widget::row::with_capacity(2)
+            .push(
+                widget::secure_input("PIN", self.passkey_pin_input.clone(), None, true)
+                    .on_input(Message::UpdatePasskeyPinInput)
+                    .on_submit(|_| Message::LoadPasskeys),
+            )
+            .push(
+                widget::button::text(if loading {
+                    "Loading..."
+                } else {
+                    "Show passkeys"
+                })
+                .on_press_maybe(
+                    (!loading && !self.passkey_pin_input.is_empty())
+                        .then_some(Message::LoadPasskeys),
+                ),
+            )
+            .spacing(padding)
+            .align_y(Alignment::Center);
+        let mut section = widget::column::with_capacity(4)
+            .push(widget::text::title4("Passkeys"))
+            .spacing(padding);
+        if let Some(Err(why) | Ok(why)) = &self.passkey_result {
+            section = section.push(widget::text(why.clone()));
+        }
+        let passkeys = match &self.passkeys {
+            None => {
+                return section
+                    .push(widget::text(
+                        "Enter the PIN to see the passkeys stored on this key.",
+                    ))
+                    .push(unlock_row)
+                    .into();
+            }
+            Some(Err(why)) => {
+                return section
+                    .push(widget::text(format!("Could not list passkeys: {why}")))
+                    .push(unlock_row)
+                    .into();
+            }
+            Some(Ok(passkeys)) => passkeys,
+        };
+
+        let count: usize = passkeys
+            .relying_parties
+            .iter()
+            .map(|rp| rp.passkeys.len())
+            .sum();
+        section = section.push(widget::text(format!(
+            "{count} passkeys, room for {} more.",
+            passkeys.remaining
+        )));
+        for rp in &passkeys.relying_parties {
+            let title = match &rp.name {
+                Some(name) if *name != rp.id => format!("{name} ({})", rp.id),
+                _ => rp.id.clone(),
+            };
+            let mut card = widget::column::with_capacity(1 + rp.passkeys.len())
+                .push(widget::text::title4(title))
+                .spacing(padding);
+            for passkey in &rp.passkeys {
+                let deleting = self.is_pending(&Operation::DeletePasskey(passkey.id.clone()));
+                let mut details =
+                    widget::column::with_capacity(3).push(widget::text(passkey.account()));
+                if let Some(user_name) = passkey
+                    .user_name
+                    .as_ref()
+                    .filter(|name| passkey.display_name.as_ref() != Some(*name))
+                {
+                    details = details.push(widget::text::caption(user_name));
+                }
+                details = details.push(widget::text::caption(format!(
+                    "{}. {}, credential {}",
+                    passkey.protection(),
+                    passkey.algorithm(),
+                    &hex::encode(&passkey.id)[..16.min(passkey.id.len() * 2)]
+                )));
+                card = card.push(
+                    widget::row::with_capacity(2)
+                        .push(details.width(Length::Fill))
+                        .push(
+                            widget::button::destructive(if deleting {
+                                "Deleting..."
+                            } else {
+                                "Delete"
+                            })
+                            .on_press_maybe(
+                                (!deleting)
+                                    .then(|| Message::PromptDeletePasskey(passkey.id.clone())),
+                            ),
+                        )
+                        .spacing(padding)
+                        .align_y(Alignment::Center),
+                );
+            }
+            section = section.push(
+                widget::container(card)
+                    .padding(padding)
+                    .width(Length::Fill)
+                    .class(theme::Container::Card),
+            );
+        }
+        section.into()
+    }
+    /// The self-test, which shows whether the key can register and sign in the way websites ask it to
+    fn self_test_section(&self) -> cosmic::Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let running = self.is_pending(&Operation::SelfTest);
+        let mut section = widget::column::with_capacity(4)
+            .push(widget::text::title4("Self-test"))
+            .push(widget::text(
+                "Registers with a made-up website and signs in to it, without a network connection. The key asks for two touches. Nothing is stored on the key.",
+            ))
+            .spacing(padding);
+        let run_button = widget::button::text(if running {
+            "Testing..."
         } else {
-            Task::none()
+            "Run self-test"
+        })
+        .on_press_maybe((!running).then_some(Message::RunSelfTest));
+        // Keys with a PIN only register with it. Once it's entered, for this or for the passkeys,
+        // the field can be left empty.
+        if let Some(Ok(PinStatus { set: true, .. })) = &self.pin_status {
+            section = section.push(
+                widget::row::with_capacity(2)
+                    .push(
+                        widget::secure_input("PIN", self.self_test_pin_input.clone(), None, true)
+                            .on_input(Message::UpdateSelfTestPinInput)
+                            .on_submit(|_| Message::RunSelfTest),
+                    )
+                    .push(run_button)
+                    .spacing(padding)
+                    .align_y(Alignment::Center),
+            );
+        } else {
+            section = section.push(run_button);
         }
+        let report = match &self.self_test {
+            None => return section.into(),
+            Some(Err(why)) => {
+                return section
+                    .push(widget::text(format!("Self-test failed: {why}")))
+                    .into();
+            }
+            Some(Ok(report)) => report,
+        };
+
+        let check = |result: Result<&str, &String>| match result {
+            Ok(outcome) => format!("passed, {outcome}"),
+            Err(why) => format!("failed: {why}"),
+        };
+        let mut card = widget::column::with_capacity(8)
+            .push(widget::text::title4(if report.passed() {
+                "Self-test passed"
+            } else {
+                "Self-test failed"
+            }))
+            .push(widget::text(format!(
+                "Registering {}",
+                report.register.description()
+            )))
+            .push(widget::text(format!(
+                "Signing in {}",
+                report.authenticate.description()
+            )))
+            .push(widget::text(format!(
+                "Registration signature: {}",
+                check(report.attestation.as_ref().map(|outcome| *outcome))
+            )))
+            .push(widget::text(format!(
+                "Sign-in signature: {}",
+                check(
+                    report
+                        .assertion
+                        .as_ref()
+                        .map(|_| "matches the new credential")
+                )
+            )))
+            .push(widget::text(if report.user_present {
+                "The key confirmed a touch both times."
+            } else {
+                "The key didn't confirm a touch, which websites refuse."
+            }))
+            .push(widget::text(format!(
+                "Signature counter: {} after registering, {} after signing in{}",
+                report.counters.0,
+                report.counters.1,
+                if report.counters_ok() {
+                    ""
+                } else {
+                    ". It didn't go up, so websites may take the key for a clone."
+                }
+            )))
+            .spacing(padding);
+        if !report.aaguid.is_empty() {
+            card = card.push(widget::text::caption(format!("AAGUID {}", report.aaguid)));
+        }
+        section
+            .push(
+                widget::container(card)
+                    .padding(padding)
+                    .width(Length::Fill)
+                    .class(theme::Container::Card),
+            )
+            .into()
     }
-    pub fn update_devices(&mut self) {
-        // Get rid of solo2 device to ensure connection to device is broken so it will be reset when the smart card state is refreshed, like when adding or deleting a key
-        self.solo2 = Option::None;
-        self.solo2 = Self::get_device();
-        if self.solo2.is_some() {
-            self.totp_list = Self::get_device_info(self.solo2.as_mut().unwrap());
-        }
-    }
-    fn get_device() -> Option<solo2::Solo2> {
-        // Set up device and totp_list fields
-        let solo2_device: Option<solo2::Solo2>;
-        let mut devices = solo2::Device::list();
-        if devices.len() == 0 {
-            solo2_device = Option::None;
+    /// Resetting FIDO2, the way out of a forgotten or blocked PIN
+    fn reset_section(&self) -> cosmic::Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let resetting = self.is_pending(&Operation::ResetFido);
+        widget::column::with_capacity(4)
+            .push(widget::text::title4("Reset FIDO2"))
+            .push(widget::text(
+                "Deletes every passkey and the PIN, so the key can be set up again. Other applets like TOTP aren't touched.",
+            ))
+            .push_maybe(match &self.reset_result {
+                Some(Ok(outcome) | Err(outcome)) => Some(widget::text(outcome.clone())),
+                None => None,
+            })
+            .push(
+                widget::button::destructive(if resetting {
+                    "Resetting..."
+                } else {
+                    "Reset FIDO2"
+                })
+                .on_press_maybe((!resetting).then_some(Message::PromptResetFido)),
+            )
+            .spacing(padding)
+            .into()
+    }
+    /// The dialog shown over the FIDO2 page, if there is one
+    fn fido_dialog(&self) -> Option<cosmic::Element<'_, Message>> {
+        if let Some(stage) = self.reset_stage {
+            let cancelling = self.reset_cancel.load(Ordering::Relaxed);
+            let cancel_button = widget::button::text(if cancelling {
+                "Cancelling..."
+            } else {
+                "Cancel"
+            })
+            .on_press_maybe((stage.can_cancel() && !cancelling).then_some(Message::CancelReset));
+            return Some(
+                widget::dialog()
+                    .title("Resetting FIDO2")
+                    .body(stage.description())
+                    .primary_action(cancel_button)
+                    .into(),
+            );
+        }
+        if self.confirming_reset {
+            return Some(
+                widget::dialog()
+                    .title("Reset FIDO2?")
+                    .body(
+                        "Every passkey on the key is deleted for good, along with the PIN. Make sure you can still sign in to your accounts some other way.\n\nKeys only accept a reset right after they're plugged in, so you'll be asked to unplug the key, plug it back in and touch it.",
+                    )
+                    .primary_action(
+                        widget::button::text("Cancel").on_press(Message::CancelResetFido),
+                    )
+                    .secondary_action(
+                        widget::button::destructive("Reset").on_press(Message::StartResetFido),
+                    )
+                    .into(),
+            );
+        }
+        self.delete_passkey_dialog()
+    }
+    /// Asks before deleting a passkey, since the account it belongs to may have no other way in
+    fn delete_passkey_dialog(&self) -> Option<cosmic::Element<'_, Message>> {
+        let id = self.deleting_passkey.as_ref()?;
+        let Some(Ok(passkeys)) = &self.passkeys else {
+            return None;
+        };
+        let (rp, passkey) = passkeys.relying_parties.iter().find_map(|rp| {
+            rp.passkeys
+                .iter()
+                .find(|passkey| passkey.id == *id)
+                .map(|passkey| (rp, passkey))
+        })?;
+        Some(
+            widget::dialog()
+                .title("Delete Passkey?")
+                .body(format!(
+                    "The passkey for {} on {} is deleted from the key for good. Make sure you can still sign in to {} some other way.",
+                    passkey.account(),
+                    rp.id,
+                    rp.id
+                ))
+                .primary_action(
+                    widget::button::text("Cancel").on_press(Message::CancelDeletePasskey),
+                )
+                .secondary_action(
+                    widget::button::destructive("Delete")
+                        .on_press(Message::DeletePasskey(id.clone())),
+                )
+                .into(),
+        )
+    }
+    /// What the key says it supports, from authenticatorGetInfo
+    fn fido_info_section(&self) -> cosmic::Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let mut card = widget::column::with_capacity(16)
+            .push(widget::text::title4("Authenticator info"))
+            .spacing(padding);
+        let info = match &self.fido_info {
+            None => return card.push(widget::text("Reading...")).into(),
+            Some(Err(why)) => {
+                return card
+                    .push(widget::text(format!(
+                        "Could not read authenticator info: {why}"
+                    )))
+                    .into();
+            }
+            Some(Ok(info)) => info,
+        };
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                "none".to_string()
+            } else {
+                items.join(", ")
+            }
+        };
+        let number = |value: Option<u64>| match value {
+            Some(value) => value.to_string(),
+            None => "not reported".to_string(),
+        };
+        // Options the key leaves out are unsupported, and listed ones say whether they're on
+        let option = |name: &str, on: &'static str, off: &'static str| match info.options.get(name)
+        {
+            Some(true) => on,
+            Some(false) => off,
+            None => "not supported",
+        };
+        let credential_management = if info.options.contains_key("credMgmt") {
+            option("credMgmt", "supported", "not supported")
         } else {
-            // Convert from Device type to Solo2 type
-            solo2_device = Option::Some(
-                devices
-                    .swap_remove(0)
-                    .into_solo2()
-                    .expect("Device is not a solo2 device."),
+            option(
+                "credentialMgmtPreview",
+                "supported (preview)",
+                "not supported",
+            )
+        };
+        let other_options: Vec<String> = info
+            .options
+            .iter()
+            .filter(|(name, _)| {
+                !["rk", "uv", "clientPin", "credMgmt", "credentialMgmtPreview"]
+                    .contains(&name.as_str())
+            })
+            .map(|(name, on)| format!("{name} {}", if *on { "on" } else { "off" }))
+            .collect();
+        let algorithms: Vec<String> = info
+            .algorithms
+            .iter()
+            .map(|algorithm| ctap2::algorithm_name(*algorithm))
+            .collect();
+        let pin_protocols: Vec<String> = info
+            .pin_protocols
+            .iter()
+            .map(|protocol| protocol.to_string())
+            .collect();
+        let rows = [
+            ("Versions", list(&info.versions)),
+            ("Extensions", list(&info.extensions)),
+            ("AAGUID", info.aaguid.clone()),
+            (
+                "Discoverable credentials",
+                option("rk", "supported", "not supported").to_string(),
+            ),
+            (
+                "Built-in user verification",
+                option("uv", "set up", "supported, not set up").to_string(),
+            ),
+            (
+                "Client PIN",
+                option("clientPin", "supported, PIN set", "supported, no PIN set").to_string(),
+            ),
+            ("Credential management", credential_management.to_string()),
+            ("Other options", list(&other_options)),
+            ("Algorithms", list(&algorithms)),
+            ("PIN protocols", list(&pin_protocols)),
+            ("Transports", list(&info.transports)),
+            (
+                "Max credentials per request",
+                number(info.max_credential_count_in_list),
+            ),
+            (
+                "Max credential ID length",
+                number(info.max_credential_id_length),
+            ),
+            ("Max message size", number(info.max_msg_size)),
+            ("Minimum PIN length", info.min_pin_length.to_string()),
+            ("Firmware version", number(info.firmware_version)),
+            (
+                "Remaining discoverable credentials",
+                number(info.remaining_discoverable_credentials),
+            ),
+        ];
+        for (name, value) in rows {
+            card = card.push(
+                widget::row::with_capacity(2)
+                    .push(widget::text(format!("{name}:")).width(250))
+                    .push(widget::text(value).width(Length::Fill))
+                    .spacing(padding),
+            );
+        }
+        widget::container(card)
+            .padding(padding)
+            .width(Length::Fill)
+            .class(theme::Container::Card)
+            .into()
+    }
+    /// Why the typed new PIN can't be saved, if it can't
+    fn new_pin_problem(&self) -> Option<String> {
+        let min_length = match &self.pin_status {
+            Some(Ok(status)) => status.min_length,
+            _ => 4,
+        };
+        client_pin::pin_problem(&self.new_pin_input, min_length).or_else(|| {
+            (self.new_pin_input != self.confirm_pin_input)
+                .then(|| "The PINs don't match.".to_string())
+        })
+    }
+    /// The page listing what the diagnostics found and how to fix it
+    fn diagnostics_page(&self) -> Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        let mut findings = widget::column::with_capacity(self.diagnostics.len()).spacing(padding);
+        for finding in &self.diagnostics {
+            let status = if finding.ok { "OK" } else { "Problem" };
+            let mut card = widget::column::with_capacity(3)
+                .push(
+                    widget::row::with_capacity(2)
+                        .push(widget::text::title4(finding.check).width(Length::Fill))
+                        .push(widget::text(status)),
+                )
+                .push(widget::text(&finding.explanation))
+                .spacing(padding);
+            if let Some(fix) = &finding.fix {
+                card = card.push(
+                    widget::row::with_capacity(2)
+                        .push(widget::text::monotext(fix).width(Length::Fill))
+                        .push(widget::button::text("Copy").on_press(Message::CopyFix(fix.clone())))
+                        .spacing(padding)
+                        .align_y(Alignment::Center),
+                );
+            }
+            findings = findings.push(
+                widget::container(card)
+                    .padding(padding)
+                    .width(Length::Fill)
+                    .class(theme::Container::Card),
             );
         }
-        solo2_device
+
+        let run_button = widget::button::text(if self.diagnosing {
+            "Checking..."
+        } else {
+            "Check again"
+        })
+        .on_press_maybe((!self.diagnosing).then_some(Message::RunDiagnostics));
+
+        widget::column::with_capacity(3)
+            .push(widget::text(
+                "Checks the usual reasons a Solo 2 isn't found on Linux.",
+            ))
+            .push(findings)
+            .push(run_button)
+            .spacing(padding)
+            .into()
     }
-    fn get_device_info(solo2_device: &mut solo2::Solo2) -> Vec<(String, String)> {
-        // Oath app
-        let mut app = Oath::select(solo2_device).expect("Could not enter OATH app:");
-        let app_list = app
-            .list()
-            .unwrap_or_else(|_| vec!["No TOTP codes.".to_string()]);
-        let mut totp_list: Vec<(String, String)> = vec![];
+    /// The page for checking which credential a typed code belongs to
+    fn verify_page(&self) -> Element<'_, Message> {
+        let padding: u16 = cosmic::theme::spacing().space_xs;
+        if self.uuid.is_empty() {
+            return widget::text("No solo2 devices.").into();
+        }
 
-        for label in app_list.iter() {
-            let totp_code = app
-                .authenticate(oath::Authenticate::with_label(&label))
-                .expect("No TOTP");
-            totp_list.push((label.to_string(), totp_code));
+        let code_input = widget::text_input("6 or 8 digit code", self.verify_input.clone())
+            .on_input(Message::UpdateVerifyInput)
+            .on_submit(|_| Message::VerifyCode);
+        let verifying = self.is_pending(&Operation::Verify);
+        let mut verify_button =
+            widget::button::text(if verifying { "Verifying..." } else { "Verify" })
+                .class(theme::Button::Suggested);
+        if Self::is_valid_code(self.verify_input.trim()) && !verifying {
+            verify_button = verify_button.on_press(Message::VerifyCode);
         }
-        totp_list
+        let input_row: cosmic::Element<Message> = widget::row::with_capacity(2)
+            .push(code_input)
+            .push(verify_button)
+            .spacing(padding)
+            .into();
+
+        let mut results = widget::column::with_capacity(1).spacing(padding);
+        match &self.verify_matches {
+            None => (),
+            Some(matches) if matches.is_empty() => {
+                results = results.push(widget::text(
+                    "No credential on this device produces that code right now.",
+                ));
+            }
+            Some(matches) => {
+                for (label, window) in matches {
+                    let window_text = match window {
+                        -1 => "previous window",
+                        0 => "current window",
+                        _ => "next window",
+                    };
+                    results = results.push(
+                        widget::container(
+                            widget::row::with_capacity(2)
+                                .push(widget::text::title3(label.clone()).width(Length::Fill))
+                                .push(widget::text(window_text))
+                                .spacing(padding)
+                                .align_y(Alignment::Center),
+                        )
+                        .padding(padding)
+                        .width(Length::Fill)
+                        .class(theme::Container::Card),
+                    );
+                }
+            }
+        }
+
+        widget::column::with_capacity(3)
+            .push(widget::text(
+                "Type a code to check which credential on the device produced it.",
+            ))
+            .push(input_row)
+            .push(results)
+            .spacing(padding)
+            .width(1000)
+            .apply(widget::container)
+            .width(Length::Fill)
+            .align_x(Alignment::Center)
+            .into()
     }
 }
 
@@ -633,6 +2863,10 @@ impl AppModel {
 pub enum Page {
     Oath,
     Admin,
+    Verify,
+    Calculate,
+    Fido,
+    Diagnostics,
 }
 
 // /// The context page to display in the context drawer.
@@ -656,3 +2890,118 @@ pub enum Page {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::tests::Harness;
+    use cosmic::Application;
+    use cosmic::iced::advanced::widget::Tree;
+
+    /// The app over a worker with the simulated devices, without a window. The clock is frozen
+    /// where RFC 6238 has a test vector.
+    struct Headless {
+        app: AppModel,
+        worker: Harness,
+    }
+
+    impl Headless {
+        fn start() -> Self {
+            let flags = Flags {
+                demo: true,
+                time: Some(59),
+            };
+            let (app, _) = AppModel::init(cosmic::Core::default(), flags);
+            let worker = Harness::start();
+            let mut headless = Headless { app, worker };
+            let sender = headless.worker.commands.clone();
+            headless.update(Message::Worker(worker::Event::Ready(sender)));
+            headless
+        }
+        /// Hands the app a message, then everything the worker does about it until nothing is
+        /// pending, like the runtime would
+        fn update(&mut self, message: Message) {
+            _ = self.app.update(message);
+            while !self.app.pending.is_empty() {
+                let event = self
+                    .worker
+                    .events
+                    .blocking_recv()
+                    .expect("The worker is running");
+                _ = self.app.update(Message::Worker(event));
+            }
+        }
+        fn labels(&self) -> Vec<&str> {
+            self.app
+                .totp_list
+                .iter()
+                .map(|credential| credential.label.as_str())
+                .collect()
+        }
+        /// How many widgets the page has, which grows with every row and dialog shown
+        fn widgets(&self) -> usize {
+            fn count(tree: &Tree) -> usize {
+                1 + tree.children.iter().map(count).sum::<usize>()
+            }
+            count(&Tree::new(&self.app.view()))
+        }
+    }
+
+    #[test]
+    fn adds_a_totp_credential_through_the_form() {
+        let mut headless = Headless::start();
+        assert_eq!(headless.labels(), ["GitHub", "Mail", "VPN"]);
+        let listed = headless.widgets();
+
+        headless.update(Message::AddTOTPButton);
+        assert!(headless.app.adding_totp);
+        let adding = headless.widgets();
+        assert!(
+            adding > listed,
+            "{adding} widgets with the form, {listed} without"
+        );
+
+        headless.update(Message::UpdateLabelInput("Test".to_string()));
+        // Secrets of the wrong length are turned down before they reach the key
+        headless.update(Message::UpdateSecretInput("JBSWY3DP".to_string()));
+        headless.update(Message::AddTOTPCode);
+        assert!(headless.app.invalid_totp_code_length);
+        assert!(headless.app.adding_totp);
+        assert_eq!(headless.labels(), ["GitHub", "Mail", "VPN"]);
+
+        headless.update(Message::UpdateSecretInput("JBSWY3DPEHPK3PXP".to_string()));
+        assert_eq!(headless.app.label_input, "Test");
+        assert_eq!(headless.app.secret_input, "JBSWY3DPEHPK3PXP");
+        headless.update(Message::AddTOTPCode);
+        assert!(headless.app.error.is_none());
+        assert!(!headless.app.invalid_totp_code_length);
+        assert!(!headless.app.adding_totp);
+        assert_eq!(headless.labels(), ["GitHub", "Mail", "VPN", "Test"]);
+        let credential = headless.app.totp_list.last().unwrap();
+        assert!(!credential.hotp);
+        assert_eq!(credential.code.as_ref().map(String::len), Ok(6));
+        assert!(headless.widgets() > listed);
+    }
+
+    #[test]
+    fn deletes_a_credential_once_confirmed() {
+        let mut headless = Headless::start();
+        let listed = headless.widgets();
+
+        headless.update(Message::PromptDeleteTOTP("Mail".to_string()));
+        assert_eq!(headless.app.deleting_totp.as_deref(), Some("Mail"));
+        // Asking doesn't delete anything yet
+        assert_eq!(headless.labels(), ["GitHub", "Mail", "VPN"]);
+        let confirming = headless.widgets();
+        assert!(
+            confirming > listed,
+            "{confirming} widgets with the dialog, {listed} without"
+        );
+
+        headless.update(Message::DeleteTOTP("Mail".to_string()));
+        assert!(headless.app.error.is_none());
+        assert!(headless.app.deleting_totp.is_none());
+        assert_eq!(headless.labels(), ["GitHub", "VPN"]);
+        assert!(headless.widgets() < listed);
+    }
+}
@@ -0,0 +1,312 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! The operations the app needs from a Solo2 device, so the worker can talk to real hardware
+//! through the `solo2` crate or to a simulated device in demo mode.
+
+use crate::calculate::{self, Calculation};
+use crate::config::TransportPreference;
+use crate::ctaphid::CtapHid;
+use crate::error::{self, Context, Error};
+use crate::usb::{self, Connection};
+use anyhow::Context as _;
+use solo2::Select;
+use solo2::apps::{Admin, Oath, oath};
+use std::fmt;
+use std::path::Path;
+
+/// Applets a Solo 2 may have, by name and AID
+const APPLETS: [(&str, &[u8]); 5] = [
+    (
+        "Admin",
+        &[0xa0, 0x00, 0x00, 0x08, 0x47, 0x00, 0x00, 0x00, 0x01],
+    ),
+    ("FIDO", &[0xa0, 0x00, 0x00, 0x06, 0x47, 0x2f, 0x00, 0x01]),
+    ("OATH", &[0xa0, 0x00, 0x00, 0x05, 0x27, 0x21, 0x01]),
+    ("PIV", &[0xa0, 0x00, 0x00, 0x03, 0x08]),
+    ("NDEF", &[0xd2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01]),
+];
+
+/// Finds devices to talk to.
+pub trait Backend: Send {
+    /// Opens every connected device
+    fn devices(&mut self) -> Vec<Box<dyn Device>>;
+    /// Opens every connected device that is in its bootloader instead of running firmware
+    fn bootloaders(&mut self) -> Vec<Box<dyn Bootloader>>;
+}
+
+/// An open connection to one device.
+pub trait Device: Send {
+    /// The device's UUID as lowercase hex
+    fn uuid(&self) -> String;
+    /// The firmware version as semver
+    fn version(&self) -> String;
+    /// The interfaces the device can be talked to over
+    fn transports(&self) -> Vec<Transport>;
+    /// The interface the device is talked to over
+    fn transport(&self) -> Transport;
+    /// Switches to talking over `transport`, which must be one of `transports`
+    fn set_transport(&mut self, transport: Transport);
+    /// How the device is plugged in
+    fn connection(&self) -> Connection;
+    /// Whether the device has an applet with the given AID that can be selected
+    fn responds_to(&mut self, aid: &[u8]) -> bool;
+    /// Whether secure boot is enabled, so only signed firmware will run
+    fn locked(&mut self) -> error::Result<bool>;
+    /// Blinks the device's LED
+    fn wink(&mut self) -> error::Result<()>;
+    /// Restarts the device, which drops the connection
+    fn reboot(&mut self) -> error::Result<()>;
+    /// Restarts the device into its bootloader, which drops the connection
+    fn boot_to_bootloader(&mut self) -> error::Result<()>;
+    /// The OATH credentials on the device
+    fn list(&mut self) -> error::Result<Vec<ListedCredential>>;
+    /// Registers an OATH credential
+    fn register(&mut self, credential: &Credential) -> error::Result<()>;
+    /// Deletes the OATH credential with the given label
+    fn delete(&mut self, label: &str) -> error::Result<()>;
+    /// Calculates the code of a credential at `timestamp`, in seconds since the unix epoch.
+    /// HOTP credentials ignore the timestamp and move their counter forward instead.
+    fn calculate(&mut self, label: &str, timestamp: u64) -> error::Result<String>;
+    /// Calculates the full and truncated HMAC of an arbitrary challenge
+    fn calculate_challenge(&mut self, label: &str, challenge: &[u8])
+    -> anyhow::Result<Calculation>;
+    /// Sends a CTAP2 message over the FIDO HID interface and returns the response, status byte
+    /// first. `touch` is called when the device waits for the user to touch it.
+    fn ctap2(&mut self, message: &[u8], touch: &dyn Fn()) -> anyhow::Result<Vec<u8>>;
+}
+
+/// An open connection to a device in its bootloader.
+pub trait Bootloader: Send {
+    /// The device's UUID as lowercase hex, which is the same as when it runs firmware
+    fn uuid(&self) -> String;
+    /// Writes a firmware image, calling `progress` with the number of bytes written so far
+    fn flash(&mut self, firmware: &[u8], progress: &dyn Fn(usize)) -> anyhow::Result<()>;
+    /// Leaves the bootloader and starts the firmware
+    fn reboot(&mut self);
+}
+
+/// An interface of the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// The smart card interface, through pcscd
+    Pcsc,
+    /// The FIDO HID interface, through hidraw
+    Ctaphid,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Pcsc => write!(f, "PC/SC"),
+            Transport::Ctaphid => write!(f, "CTAPHID"),
+        }
+    }
+}
+
+/// Switches the device to the preferred transport if it has it, and otherwise explains
+/// which transport is used instead and why
+pub fn choose_transport(
+    device: &mut dyn Device,
+    preference: TransportPreference,
+) -> Option<String> {
+    let wanted = match preference {
+        // The solo2 crate picks in automatic mode, but a missing PC/SC still deserves an
+        // explanation since it usually means pcscd isn't running
+        TransportPreference::Auto | TransportPreference::Pcsc => Transport::Pcsc,
+        TransportPreference::Ctaphid => Transport::Ctaphid,
+    };
+    if device.transports().contains(&wanted) {
+        if preference != TransportPreference::Auto {
+            device.set_transport(wanted);
+        }
+        return None;
+    }
+    let reason = match wanted {
+        Transport::Pcsc => pcsc_problem()
+            .map(|why| format!(" ({why})"))
+            .unwrap_or_default(),
+        Transport::Ctaphid => "".to_string(),
+    };
+    Some(format!(
+        "{wanted} isn't available for this device{reason}, so {} is used instead.",
+        device.transport()
+    ))
+}
+
+/// Names of the applets the device lets us select
+pub fn applets(device: &mut dyn Device) -> Vec<&'static str> {
+    APPLETS
+        .into_iter()
+        .filter(|(_, aid)| device.responds_to(aid))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Why PC/SC can't be used at all, like pcscd not running
+pub fn pcsc_problem() -> Option<String> {
+    pcsc::Context::establish(pcsc::Scope::User)
+        .err()
+        .map(|why| why.to_string())
+}
+
+/// A credential to register, as typed into the add widget.
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub label: String,
+    /// The secret, base32 encoded
+    pub secret: String,
+    /// Whether this is a counter based HOTP credential instead of TOTP
+    pub hotp: bool,
+}
+
+/// A credential on the device, as the device lists it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedCredential {
+    pub label: String,
+    /// Whether this is a counter based HOTP credential, whose codes are used up by calculating them
+    pub hotp: bool,
+}
+
+/// Talks to real devices through the `solo2` crate.
+pub struct Solo2Backend;
+
+impl Backend for Solo2Backend {
+    fn devices(&mut self) -> Vec<Box<dyn Device>> {
+        solo2::Device::list()
+            .into_iter()
+            // Convert from Device type to Solo2 type, skipping devices that aren't running Solo2 firmware
+            .filter_map(|device| device.into_solo2())
+            .map(|solo2| Box::new(solo2) as Box<dyn Device>)
+            .collect()
+    }
+    fn bootloaders(&mut self) -> Vec<Box<dyn Bootloader>> {
+        solo2::Device::list()
+            .into_iter()
+            .filter_map(|device| match device {
+                solo2::Device::Lpc55(bootloader) => {
+                    Some(Box::new(bootloader) as Box<dyn Bootloader>)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Bootloader for lpc55::Bootloader {
+    fn uuid(&self) -> String {
+        format!("{:032x}", self.uuid)
+    }
+    fn flash(&mut self, firmware: &[u8], progress: &dyn Fn(usize)) -> anyhow::Result<()> {
+        self.receive_sb_file(firmware, Some(progress))?;
+        Ok(())
+    }
+    fn reboot(&mut self) {
+        lpc55::Bootloader::reboot(self);
+    }
+}
+
+impl Device for solo2::Solo2 {
+    fn uuid(&self) -> String {
+        solo2::Solo2::uuid(self).simple().to_string()
+    }
+    fn version(&self) -> String {
+        solo2::Solo2::version(self).to_semver()
+    }
+    fn transports(&self) -> Vec<Transport> {
+        let mut transports = vec![];
+        if self.as_pcsc().is_some() {
+            transports.push(Transport::Pcsc);
+        }
+        if self.as_ctap().is_some() {
+            transports.push(Transport::Ctaphid);
+        }
+        transports
+    }
+    fn transport(&self) -> Transport {
+        match solo2::Solo2::transport(self) {
+            solo2::TransportPreference::Pcsc => Transport::Pcsc,
+            solo2::TransportPreference::Ctap => Transport::Ctaphid,
+        }
+    }
+    fn set_transport(&mut self, transport: Transport) {
+        solo2::Solo2::set_transport(
+            self,
+            match transport {
+                Transport::Pcsc => solo2::TransportPreference::Pcsc,
+                Transport::Ctaphid => solo2::TransportPreference::Ctap,
+            },
+        );
+    }
+    fn connection(&self) -> Connection {
+        usb::connection(&Device::uuid(self))
+    }
+    fn responds_to(&mut self, aid: &[u8]) -> bool {
+        // SELECT by AID
+        solo2::Transport::call_iso(self, 0x00, 0xa4, 0x04, 0x00, aid).is_ok()
+    }
+    fn locked(&mut self) -> error::Result<bool> {
+        let mut admin_app = Admin::select(self).selecting("admin")?;
+        admin_app
+            .locked()
+            .during("find out if the device is locked")
+    }
+    fn wink(&mut self) -> error::Result<()> {
+        let mut admin_app = Admin::select(self).selecting("admin")?;
+        admin_app.wink().during("wink the device")
+    }
+    fn reboot(&mut self) -> error::Result<()> {
+        let mut admin_app = Admin::select(self).selecting("admin")?;
+        admin_app.reboot().during("reboot the device")
+    }
+    fn boot_to_bootloader(&mut self) -> error::Result<()> {
+        let mut admin_app = Admin::select(self).selecting("admin")?;
+        admin_app
+            .boot_to_bootrom()
+            .during("reboot the device into its bootloader")
+    }
+    fn list(&mut self) -> error::Result<Vec<ListedCredential>> {
+        // The solo2 crate's oath module only lists labels
+        crate::oath::list(self).during("list credentials")
+    }
+    fn register(&mut self, credential: &Credential) -> error::Result<()> {
+        let mut oath_credential =
+            oath::Credential::default_totp(&credential.label, &credential.secret)
+                .map_err(|why| Error::InvalidCredential(why.into()))?;
+        if credential.hotp {
+            oath_credential.kind = oath::Kind::Hotp;
+            oath_credential.counter = Some(0);
+        }
+
+        let mut app = Oath::select(self).selecting("OATH")?;
+        app.register(oath_credential)
+            .during("register the credential")
+    }
+    fn delete(&mut self, label: &str) -> error::Result<()> {
+        let mut app = Oath::select(self).selecting("OATH")?;
+        app.delete(label.to_string())
+            .during("delete the credential")
+    }
+    fn calculate(&mut self, label: &str, timestamp: u64) -> error::Result<String> {
+        let mut app = Oath::select(self).selecting("OATH")?;
+        app.authenticate(oath::Authenticate::with_label(label).with_timestamp(timestamp))
+            .during("calculate the code")
+    }
+    fn calculate_challenge(
+        &mut self,
+        label: &str,
+        challenge: &[u8],
+    ) -> anyhow::Result<Calculation> {
+        calculate::calculate(self, label, challenge)
+    }
+    fn ctap2(&mut self, message: &[u8], touch: &dyn Fn()) -> anyhow::Result<Vec<u8>> {
+        // A channel of our own, so this works whichever transport the solo2 crate talks over
+        let connection = Device::connection(self);
+        let node = connection
+            .hidraw
+            .first()
+            .context("The key's FIDO HID interface wasn't found")?;
+        let mut hid =
+            CtapHid::open(Path::new(node)).with_context(|| format!("Could not open {node}"))?;
+        Ok(hid.cbor(message, touch)?)
+    }
+}
@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Sends OATH CALCULATE requests with arbitrary challenges, for debugging integrations that use
+//! the applet's HMAC directly instead of TOTP codes.
+
+use anyhow::{Context, bail, ensure};
+use solo2::Select;
+use solo2::apps::{App, Oath};
+
+/// Instruction of the OATH applet's CALCULATE command
+const CALCULATE: u8 = 0xA2;
+/// P2 asking for the full HMAC
+const FULL_RESPONSE: u8 = 0x00;
+/// P2 asking for the dynamically truncated HMAC
+const TRUNCATED_RESPONSE: u8 = 0x01;
+const NAME_TAG: u8 = 0x71;
+const CHALLENGE_TAG: u8 = 0x74;
+const FULL_RESPONSE_TAG: u8 = 0x75;
+const TRUNCATED_RESPONSE_TAG: u8 = 0x76;
+/// The longest challenge the OATH applet takes
+pub const MAX_CHALLENGE_BYTES: usize = 64;
+
+/// What the device answered to a challenge for one credential
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Calculation {
+    /// Number of digits the credential's codes have
+    pub digits: u8,
+    /// The full HMAC of the challenge
+    pub hmac: Vec<u8>,
+    /// The dynamically truncated HMAC, as calculated by the device
+    pub truncated: u32,
+}
+
+impl Calculation {
+    /// The code the truncated HMAC corresponds to
+    pub fn code(&self) -> String {
+        // Truncated HMACs have 31 bits, so codes of ten digits or more are the whole value
+        let code = match 10u32.checked_pow(u32::from(self.digits)) {
+            Some(modulus) => self.truncated % modulus,
+            None => self.truncated,
+        };
+        format!("{code:0width$}", width = self.digits as usize)
+    }
+    /// Truncates the full HMAC as described in RFC 4226, to cross-check the device's truncation
+    pub fn truncate_hmac(&self) -> Option<u32> {
+        let offset = (*self.hmac.last()? & 0x0f) as usize;
+        let bytes: [u8; 4] = self.hmac.get(offset..offset + 4)?.try_into().ok()?;
+        Some(u32::from_be_bytes(bytes) & 0x7fff_ffff)
+    }
+}
+
+/// Asks the device for the full and truncated HMAC of the challenge with the credential labelled `label`
+pub fn calculate(
+    solo2_device: &mut solo2::Solo2,
+    label: &str,
+    challenge: &[u8],
+) -> anyhow::Result<Calculation> {
+    ensure!(
+        challenge.len() <= MAX_CHALLENGE_BYTES,
+        "The challenge is {} bytes, but the OATH applet takes at most {MAX_CHALLENGE_BYTES}",
+        challenge.len()
+    );
+    let mut data = tlv(NAME_TAG, label.as_bytes())?;
+    data.extend(tlv(CHALLENGE_TAG, challenge)?);
+    let mut app = Oath::select(solo2_device)?;
+
+    let full = app
+        .transport()
+        .call_iso(0x00, CALCULATE, 0x00, FULL_RESPONSE, &data)
+        .context("Device refused to calculate the full HMAC")?;
+    let (digits, hmac) = response(&full, FULL_RESPONSE_TAG)?;
+
+    let truncated = app
+        .transport()
+        .call_iso(0x00, CALCULATE, 0x00, TRUNCATED_RESPONSE, &data)
+        .context("Device refused to calculate the truncated HMAC")?;
+    let (_, truncated) = response(&truncated, TRUNCATED_RESPONSE_TAG)?;
+    let Ok(truncated) = <[u8; 4]>::try_from(truncated) else {
+        bail!(
+            "Truncated response should be 4 bytes, got {}",
+            truncated.len()
+        );
+    };
+
+    Ok(Calculation {
+        digits,
+        hmac: hmac.to_vec(),
+        truncated: u32::from_be_bytes(truncated) & 0x7fff_ffff,
+    })
+}
+
+/// Encodes a short tag-length-value entry, whose length has to fit in a byte
+fn tlv(tag: u8, value: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let Ok(length) = u8::try_from(value.len()) else {
+        bail!("{} bytes is too long for a request field", value.len());
+    };
+    let mut entry = vec![tag, length];
+    entry.extend_from_slice(value);
+    Ok(entry)
+}
+
+/// Splits a response entry into the digits byte and the value that follows it
+fn response(data: &[u8], expected_tag: u8) -> anyhow::Result<(u8, &[u8])> {
+    match data {
+        [tag, length, digits, value @ ..]
+            if *tag == expected_tag && *length as usize == value.len() + 1 =>
+        {
+            Ok((*digits, value))
+        }
+        _ => bail!("Unexpected response from device: {}", hex::encode(data)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The HMAC of the RFC 4226 test secret with counter 0, from its appendix D
+    fn rfc4226_calculation(digits: u8) -> Calculation {
+        Calculation {
+            digits,
+            hmac: hex::decode("cc93cf18508d94934c64b65d8ba7667fb7cde4b0").unwrap(),
+            truncated: 0x4c93cf18,
+        }
+    }
+
+    #[test]
+    fn truncates_like_rfc4226() {
+        let calculation = rfc4226_calculation(6);
+        assert_eq!(calculation.truncate_hmac(), Some(calculation.truncated));
+        assert_eq!(calculation.code(), "755224");
+    }
+
+    #[test]
+    fn pads_codes_with_zeros() {
+        let calculation = Calculation {
+            digits: 8,
+            hmac: vec![],
+            truncated: 1234,
+        };
+        assert_eq!(calculation.code(), "00001234");
+    }
+
+    #[test]
+    fn keeps_whole_value_for_long_codes() {
+        assert_eq!(rfc4226_calculation(9).code(), "284755224");
+        assert_eq!(rfc4226_calculation(10).code(), "1284755224");
+        assert_eq!(rfc4226_calculation(12).code(), "001284755224");
+    }
+
+    #[test]
+    fn rejects_fields_longer_than_a_byte() {
+        assert_eq!(
+            tlv(NAME_TAG, b"VPN").unwrap(),
+            [NAME_TAG, 3, b'V', b'P', b'N']
+        );
+        assert_eq!(tlv(CHALLENGE_TAG, &[0; 255]).unwrap().len(), 257);
+        assert!(tlv(CHALLENGE_TAG, &[0; 256]).is_err());
+    }
+
+    #[test]
+    fn splits_responses() {
+        let (digits, value) = response(
+            &[TRUNCATED_RESPONSE_TAG, 5, 6, 1, 2, 3, 4],
+            TRUNCATED_RESPONSE_TAG,
+        )
+        .unwrap();
+        assert_eq!((digits, value), (6, &[1, 2, 3, 4][..]));
+        assert!(response(&[TRUNCATED_RESPONSE_TAG, 5, 6, 1], TRUNCATED_RESPONSE_TAG).is_err());
+        assert!(response(&[FULL_RESPONSE_TAG, 2, 6, 1], TRUNCATED_RESPONSE_TAG).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Finds the newest firmware release in a release feed, so the app can say when a key runs old
+//! firmware. Feeds use the format of GitHub's releases API, either a list of releases or a single
+//! one, and can be mirrored to another URL or a local file for air-gapped sites.
+
+use anyhow::Context;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// The upstream release feed, used when no other feed is configured
+pub const UPSTREAM_FEED: &str = "https://api.github.com/repos/solokeys/solo2/releases";
+/// How long fetching a feed may take in all, so a hanging server doesn't keep the check running
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A firmware release.
+#[derive(Debug, Clone)]
+pub struct Release {
+    /// The firmware version, as the device reports it
+    pub version: String,
+    /// What changed, as written by the release's authors
+    pub notes: String,
+    /// Where the release can be looked at and downloaded
+    pub url: String,
+}
+
+/// A release, as the feed describes it
+#[derive(Deserialize)]
+struct FeedRelease {
+    tag_name: String,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    html_url: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+}
+
+/// A feed holds every release, or only the latest one
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Feed {
+    Releases(Vec<FeedRelease>),
+    Release(FeedRelease),
+}
+
+/// Reads the feed at `feed`, a URL or a local path, and returns the newest stable release in it.
+/// This blocks, so it shouldn't run on the interface's thread.
+pub fn latest(feed: &str) -> anyhow::Result<Option<Release>> {
+    let feed = feed.trim();
+    let feed = if feed.is_empty() { UPSTREAM_FEED } else { feed };
+    let json = if feed.starts_with("https://") || feed.starts_with("http://") {
+        ureq::get(feed)
+            .config()
+            .timeout_global(Some(FETCH_TIMEOUT))
+            .build()
+            .header(
+                "User-Agent",
+                concat!("solo2-gui/", env!("CARGO_PKG_VERSION")),
+            )
+            .call()
+            .with_context(|| format!("Could not fetch {feed}"))?
+            .body_mut()
+            .read_to_string()
+            .with_context(|| format!("Could not read {feed}"))?
+    } else {
+        let path = feed.strip_prefix("file://").unwrap_or(feed);
+        std::fs::read_to_string(path).with_context(|| format!("Could not read {path}"))?
+    };
+    let releases = match serde_json::from_str(&json).context("Release feed isn't valid")? {
+        Feed::Releases(releases) => releases,
+        Feed::Release(release) => vec![release],
+    };
+
+    Ok(releases
+        .into_iter()
+        .filter(|release| !release.draft && !release.prerelease)
+        .filter_map(|release| Some((version_numbers(&release.tag_name)?, release)))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, release)| Release {
+            version: release.tag_name.trim_start_matches('v').to_string(),
+            notes: release.body.unwrap_or_default().trim().to_string(),
+            url: release.html_url,
+        }))
+}
+
+/// Whether `release` is a newer version than `current`
+pub fn is_newer(release: &str, current: &str) -> bool {
+    match (version_numbers(release), version_numbers(current)) {
+        (Some(release), Some(current)) => release > current,
+        _ => false,
+    }
+}
+
+/// The numbers of a version like `v2.964.0`, for comparing versions
+fn version_numbers(version: &str) -> Option<Vec<u64>> {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split('.')
+        .map(|number| number.parse().ok())
+        .collect()
+}
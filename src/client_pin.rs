@@ -0,0 +1,274 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! The clientPIN command of CTAP2, using PIN protocol one. PINs never cross the wire in the clear:
+//! the app and the key agree on a secret with ECDH over P-256, and PINs are encrypted with
+//! AES-256-CBC under its SHA-256 hash and authenticated with HMAC-SHA-256.
+
+use crate::backend::Device;
+use crate::ctap2::{self, CLIENT_PIN};
+use aes::Aes256;
+use anyhow::{Context, ensure};
+use cbc::cipher::block_padding::NoPadding;
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use ciborium::Value;
+use hmac::{Hmac, Mac};
+use p256::SecretKey;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use sha2::{Digest, Sha256};
+
+/// The PIN protocol every CTAP2 key supports
+const PROTOCOL: i64 = 1;
+/// clientPIN subcommands
+const GET_RETRIES: i64 = 0x01;
+const GET_KEY_AGREEMENT: i64 = 0x02;
+const SET_PIN: i64 = 0x03;
+const CHANGE_PIN: i64 = 0x04;
+const GET_PIN_TOKEN: i64 = 0x05;
+/// PINs are padded with zeros to this many bytes before they're encrypted
+const PADDED_PIN_SIZE: usize = 64;
+/// The longest PIN in bytes, which leaves room for at least one byte of padding
+pub const MAX_PIN_BYTES: usize = PADDED_PIN_SIZE - 1;
+
+/// Whether the key has a PIN, and how many wrong ones it still takes.
+#[derive(Debug, Clone)]
+pub struct PinStatus {
+    /// Whether a PIN is set
+    pub set: bool,
+    /// Wrong PINs the key accepts before it blocks, if a PIN is set
+    pub retries: Option<u64>,
+    /// The shortest PIN the key accepts, in characters
+    pub min_length: usize,
+}
+
+/// Finds out whether the key has a PIN and how many tries are left
+pub fn status(device: &mut dyn Device) -> anyhow::Result<PinStatus> {
+    let info = ctap2::get_info(device)?;
+    let set = info.options.get("clientPin") == Some(&true);
+    let retries = if set {
+        let response = ctap2::call(
+            device,
+            CLIENT_PIN,
+            Some(ctap2::map([
+                (1, Value::from(PROTOCOL)),
+                (2, Value::from(GET_RETRIES)),
+            ])),
+            &|| {},
+        )?;
+        Some(ctap2::get_unsigned(&response, 3).context("Key sent no PIN retry count")?)
+    } else {
+        None
+    };
+    Ok(PinStatus {
+        set,
+        retries,
+        min_length: info.min_pin_length as usize,
+    })
+}
+
+/// Sets the first PIN of a key that has none
+pub fn set_pin(device: &mut dyn Device, pin: &str) -> anyhow::Result<()> {
+    let session = Session::new(device)?;
+    let new_pin = session.encrypt(&padded(pin)?);
+    ctap2::call(
+        device,
+        CLIENT_PIN,
+        Some(ctap2::map([
+            (1, Value::from(PROTOCOL)),
+            (2, Value::from(SET_PIN)),
+            (3, session.platform_key.clone()),
+            (4, Value::Bytes(session.authenticate(&new_pin))),
+            (5, Value::Bytes(new_pin)),
+        ])),
+        &|| {},
+    )?;
+    Ok(())
+}
+
+/// Replaces the PIN of a key, which checks `current` first
+pub fn change_pin(device: &mut dyn Device, current: &str, pin: &str) -> anyhow::Result<()> {
+    let session = Session::new(device)?;
+    let new_pin = session.encrypt(&padded(pin)?);
+    let pin_hash = session.encrypt(&pin_hash(current));
+    let mut authenticated = new_pin.clone();
+    authenticated.extend_from_slice(&pin_hash);
+    ctap2::call(
+        device,
+        CLIENT_PIN,
+        Some(ctap2::map([
+            (1, Value::from(PROTOCOL)),
+            (2, Value::from(CHANGE_PIN)),
+            (3, session.platform_key.clone()),
+            (4, Value::Bytes(session.authenticate(&authenticated))),
+            (5, Value::Bytes(new_pin)),
+            (6, Value::Bytes(pin_hash)),
+        ])),
+        &|| {},
+    )?;
+    Ok(())
+}
+
+/// A token proving the PIN was entered, which stays valid until the key is unplugged or another
+/// token is asked for.
+#[derive(Clone)]
+pub struct PinToken(Vec<u8>);
+
+impl PinToken {
+    /// The pinUvAuthParam of a request, which proves the request comes from whoever has the token
+    pub fn authenticate(&self, data: &[u8]) -> Vec<u8> {
+        authenticate(&self.0, data)
+    }
+    /// The PIN protocol the token belongs to, which requests have to name
+    pub fn protocol(&self) -> Value {
+        Value::from(PROTOCOL)
+    }
+}
+
+/// Exchanges the PIN for a token. Wrong PINs use up a try.
+pub fn pin_token(device: &mut dyn Device, pin: &str) -> anyhow::Result<PinToken> {
+    let session = Session::new(device)?;
+    let response = ctap2::call(
+        device,
+        CLIENT_PIN,
+        Some(ctap2::map([
+            (1, Value::from(PROTOCOL)),
+            (2, Value::from(GET_PIN_TOKEN)),
+            (3, session.platform_key.clone()),
+            (6, Value::Bytes(session.encrypt(&pin_hash(pin)))),
+        ])),
+        &|| {},
+    )?;
+    let token = ctap2::get(&response, 2)
+        .and_then(Value::as_bytes)
+        .context("Key sent no PIN token")?;
+    Ok(PinToken(session.decrypt(token)?))
+}
+
+/// Why `pin` can't be used as a new PIN, if it can't. Keys only enforce the length, so this also
+/// turns down PINs that are easy to guess.
+pub fn pin_problem(pin: &str, min_length: usize) -> Option<String> {
+    let characters: Vec<char> = pin.chars().collect();
+    if characters.len() < min_length {
+        return Some(format!("The PIN needs at least {min_length} characters."));
+    }
+    if pin.len() > MAX_PIN_BYTES {
+        return Some(format!(
+            "The PIN can't be longer than {MAX_PIN_BYTES} bytes."
+        ));
+    }
+    if characters
+        .iter()
+        .all(|character| *character == characters[0])
+    {
+        return Some("The PIN can't be one character repeated.".to_string());
+    }
+    let steps: Vec<i64> = characters
+        .windows(2)
+        .map(|pair| pair[1] as i64 - pair[0] as i64)
+        .collect();
+    if steps.iter().all(|step| *step == 1) || steps.iter().all(|step| *step == -1) {
+        return Some("The PIN can't be a run like 1234 or abcd.".to_string());
+    }
+    None
+}
+
+/// A shared secret agreed with the key for one exchange.
+struct Session {
+    /// SHA-256 of the ECDH shared point's x coordinate
+    secret: [u8; 32],
+    /// Our public key as a COSE key, which the key needs to work out the same secret
+    platform_key: Value,
+}
+
+impl Session {
+    /// Gets the key's public key and agrees on a secret with a fresh key of our own
+    fn new(device: &mut dyn Device) -> anyhow::Result<Self> {
+        let response = ctap2::call(
+            device,
+            CLIENT_PIN,
+            Some(ctap2::map([
+                (1, Value::from(PROTOCOL)),
+                (2, Value::from(GET_KEY_AGREEMENT)),
+            ])),
+            &|| {},
+        )?;
+        let cose = ctap2::get(&response, 1).context("Key sent no key agreement key")?;
+        let authenticator_key =
+            ctap2::p256_key(cose).context("Key sent an unusable key agreement key")?;
+
+        let secret_key = random_secret_key()?;
+        let shared = p256::ecdh::diffie_hellman(
+            secret_key.to_nonzero_scalar(),
+            authenticator_key.as_affine(),
+        );
+        let public = secret_key.public_key().to_encoded_point(false);
+        let platform_key = ctap2::map([
+            // EC2 key type, ECDH-ES+HKDF-256 as CTAP2 asks for, and the P-256 curve
+            (1, Value::from(2)),
+            (3, Value::from(-25)),
+            (-1, Value::from(1)),
+            (
+                -2,
+                Value::Bytes(public.x().expect("Point isn't compressed").to_vec()),
+            ),
+            (
+                -3,
+                Value::Bytes(public.y().expect("Point isn't compressed").to_vec()),
+            ),
+        ]);
+        Ok(Session {
+            secret: Sha256::digest(shared.raw_secret_bytes()).into(),
+            platform_key,
+        })
+    }
+    /// Encrypts whole AES blocks with a zero IV, as PIN protocol one does
+    fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        cbc::Encryptor::<Aes256>::new(&self.secret.into(), &[0; 16].into())
+            .encrypt_padded_vec_mut::<NoPadding>(data)
+    }
+    /// Decrypts whole AES blocks with a zero IV
+    fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        cbc::Decryptor::<Aes256>::new(&self.secret.into(), &[0; 16].into())
+            .decrypt_padded_vec_mut::<NoPadding>(data)
+            .ok()
+            .context("Key sent encrypted data that isn't whole blocks")
+    }
+    /// The first 16 bytes of the HMAC of `data`, which proves we know the secret
+    fn authenticate(&self, data: &[u8]) -> Vec<u8> {
+        authenticate(&self.secret, data)
+    }
+}
+
+/// The first 16 bytes of the HMAC-SHA-256 of `data` under `key`
+fn authenticate(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes()[..16].to_vec()
+}
+
+/// The first 16 bytes of the SHA-256 of a PIN, which is what keys store
+fn pin_hash(pin: &str) -> Vec<u8> {
+    Sha256::digest(pin.as_bytes())[..16].to_vec()
+}
+
+/// A PIN padded with zeros to the size keys expect
+fn padded(pin: &str) -> anyhow::Result<[u8; PADDED_PIN_SIZE]> {
+    ensure!(
+        pin.len() <= MAX_PIN_BYTES,
+        "The PIN is longer than {MAX_PIN_BYTES} bytes"
+    );
+    let mut padded = [0; PADDED_PIN_SIZE];
+    padded[..pin.len()].copy_from_slice(pin.as_bytes());
+    Ok(padded)
+}
+
+/// A fresh P-256 private key from the system's random number generator
+fn random_secret_key() -> anyhow::Result<SecretKey> {
+    loop {
+        let mut bytes = [0; 32];
+        getrandom::fill(&mut bytes).context("Could not generate a key")?;
+        // Almost every 32 byte string is a valid scalar
+        if let Ok(key) = SecretKey::from_slice(&bytes) {
+            return Ok(key);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Where the time used for TOTP codes and their countdown comes from.
+
+use crate::app::TOTP_PERIOD;
+use std::time::SystemTime;
+
+/// A source of unix time, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Clock {
+    /// The system clock
+    System,
+    /// The system clock shifted by some seconds, for machines whose clock is off
+    Offset(i64),
+    /// Always the same time, for reproducible codes and screenshots
+    Fixed(u64),
+}
+
+impl Clock {
+    /// Seconds since the unix epoch
+    pub fn now(&self) -> u64 {
+        match self {
+            Clock::System => system_time(),
+            Clock::Offset(offset) => system_time().saturating_add_signed(*offset),
+            Clock::Fixed(time) => *time,
+        }
+    }
+    /// How many seconds the current TOTP window has left, from `TOTP_PERIOD` down to 1
+    pub fn seconds_left(&self) -> u64 {
+        TOTP_PERIOD - self.now() % TOTP_PERIOD
+    }
+}
+
+fn system_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("System time before unix epoch somehow.")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The TOTP window a clock is in, which is the moving factor of its codes
+    fn window(clock: Clock) -> u64 {
+        clock.now() / TOTP_PERIOD
+    }
+
+    #[test]
+    fn counts_down_to_the_next_window() {
+        assert_eq!(TOTP_PERIOD, 30);
+        for (time, seconds_left, expected_window) in
+            [(0, 30, 0), (29, 1, 0), (30, 30, 1), (31, 29, 1)]
+        {
+            let clock = Clock::Fixed(time);
+            assert_eq!(clock.now(), time);
+            assert_eq!(clock.seconds_left(), seconds_left, "at {time}");
+            assert_eq!(window(clock), expected_window, "at {time}");
+        }
+    }
+
+    #[test]
+    fn the_last_second_of_a_window_is_still_in_it() {
+        let before = Clock::Fixed(59);
+        let after = Clock::Fixed(60);
+        assert_eq!(before.seconds_left(), 1);
+        assert_eq!(after.seconds_left(), TOTP_PERIOD);
+        assert_eq!(window(after), window(before) + 1);
+    }
+
+    #[test]
+    fn offsets_shift_the_system_clock() {
+        // Read the offset clock between two system clock reads, so a second ticking over in
+        // between doesn't fail the test
+        let before = Clock::System.now();
+        let ahead = Clock::Offset(90).now();
+        let behind = Clock::Offset(-90).now();
+        let after = Clock::System.now();
+        assert!((before + 90..=after + 90).contains(&ahead));
+        assert!((before - 90..=after - 90).contains(&behind));
+    }
+
+    #[test]
+    fn offsets_before_the_epoch_stop_at_it() {
+        assert_eq!(Clock::Offset(i64::MIN).now(), 0);
+        assert_eq!(Clock::Offset(i64::MIN).seconds_left(), TOTP_PERIOD);
+    }
+}
@@ -1,9 +1,38 @@
 // SPDX-License-Identifier: AGPL-3.0
 
 use cosmic::cosmic_config::{self, CosmicConfigEntry, cosmic_config_derive::CosmicConfigEntry};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Default, Clone, CosmicConfigEntry, Eq, PartialEq)]
 #[version = 1]
 pub struct Config {
     demo: String,
+    /// How many codes each device has generated for its HOTP credentials, by UUID and then label
+    pub device_hotp_counters: BTreeMap<String, BTreeMap<String, u64>>,
+    /// UUID of the device that was selected last, so it is picked again when several are plugged in
+    pub last_device: String,
+    /// Nicknames given to devices, by UUID
+    pub device_nicknames: BTreeMap<String, String>,
+    /// Seconds added to the system clock before calculating codes, for machines whose clock is off
+    pub clock_offset: i64,
+    /// Which interface of the device to talk to
+    pub transport: TransportPreference,
+    /// Where to read firmware releases from, as a URL or local path, or empty for upstream
+    pub firmware_feed: String,
+    /// Whether to leave the release feed alone until asked, instead of reading it at startup
+    pub skip_release_check_on_startup: bool,
+}
+
+/// Which of the device's interfaces to talk to. Devices that don't have the preferred one are
+/// talked to over the other one instead.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum TransportPreference {
+    /// Whichever the solo2 crate finds first
+    #[default]
+    Auto,
+    /// The smart card interface, through pcscd
+    Pcsc,
+    /// The FIDO HID interface, through hidraw
+    Ctaphid,
 }
@@ -0,0 +1,407 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! CTAP2, the protocol of FIDO2 keys. A request is a command byte followed by a CBOR map with
+//! small integer keys, and a response is a status byte followed by a CBOR map the same way.
+
+use crate::backend::Device;
+use crate::client_pin::PinToken;
+use anyhow::{Context, ensure};
+use ciborium::Value;
+use p256::PublicKey;
+use p256::elliptic_curve::sec1::{EncodedPoint, FromEncodedPoint};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Commands
+pub const MAKE_CREDENTIAL: u8 = 0x01;
+pub const GET_ASSERTION: u8 = 0x02;
+pub const GET_INFO: u8 = 0x04;
+pub const CLIENT_PIN: u8 = 0x06;
+pub const RESET: u8 = 0x07;
+
+/// Statuses the app acts on
+pub const PIN_AUTH_INVALID: u8 = 0x33;
+pub const PIN_REQUIRED: u8 = 0x36;
+
+/// The relying party credentials are made for when checking the key, which can't belong to a
+/// real site since `.invalid` never resolves
+pub const TEST_RP_ID: &str = "solo2-gui.invalid";
+/// COSE algorithm identifier of ECDSA with P-256 and SHA-256
+pub const ES256: i64 = -7;
+
+/// A status other than success, sent by the key instead of a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CtapError(pub u8);
+
+impl fmt::Display for CtapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let meaning = match self.0 {
+            0x01 => "the key doesn't know the command",
+            0x02 => "a parameter is invalid",
+            0x11 | 0x12 | 0x14 | 0x15 => "the request is malformed",
+            0x19 => "the credential already exists",
+            0x26 => "the key doesn't support the algorithm",
+            0x27 => "the operation was denied",
+            0x28 => "the key is out of space for credentials",
+            0x2b => "the key doesn't support the option",
+            0x2e => "no credentials were found",
+            0x2f => "the key wasn't touched in time",
+            0x30 => "the key doesn't allow this right now",
+            0x31 => "the PIN is wrong",
+            0x32 => "the PIN is blocked, and only resetting FIDO2 unblocks it",
+            PIN_AUTH_INVALID => "the PIN token is invalid",
+            0x34 => "too many wrong PINs; unplug and replug the key to try again",
+            0x35 => "no PIN is set",
+            PIN_REQUIRED => "the key has a PIN, which is needed for this",
+            0x37 => "the PIN doesn't meet the key's requirements",
+            0x39 => "the request is too large",
+            0x3a => "the key took too long",
+            0x3b => "the key wasn't touched",
+            _ => "the key refused the request",
+        };
+        write!(f, "CTAP2 error {:#04x}: {meaning}", self.0)
+    }
+}
+
+impl std::error::Error for CtapError {}
+
+/// A newly made credential and the key's statement vouching for it.
+#[derive(Debug, Clone)]
+pub struct Attestation {
+    /// The attestation statement format, like "packed"
+    pub format: String,
+    /// The authenticator data, which holds the new credential
+    pub auth_data: Vec<u8>,
+    /// The attestation statement, whose fields depend on the format
+    pub statement: Value,
+}
+
+/// A signature over a challenge with an existing credential.
+#[derive(Debug, Clone)]
+pub struct Assertion {
+    /// The authenticator data, which holds the flags and signature counter
+    pub auth_data: Vec<u8>,
+    /// The signature over the authenticator data followed by the challenge
+    pub signature: Vec<u8>,
+}
+
+/// What the key says about itself in response to authenticatorGetInfo.
+#[derive(Debug, Clone, Default)]
+pub struct Info {
+    /// CTAP and U2F versions the key speaks, like "FIDO_2_0"
+    pub versions: Vec<String>,
+    /// Extensions the key supports, like "hmac-secret"
+    pub extensions: Vec<String>,
+    /// The AAGUID, which names the key's model
+    pub aaguid: String,
+    /// Options the key supports, and whether they are on. Options it doesn't list are unsupported.
+    pub options: BTreeMap<String, bool>,
+    /// The largest message the key accepts, in bytes
+    pub max_msg_size: Option<u64>,
+    /// PIN protocols the key supports, most preferred first
+    pub pin_protocols: Vec<u64>,
+    /// The most credentials a request may list
+    pub max_credential_count_in_list: Option<u64>,
+    /// The longest credential ID the key accepts, in bytes
+    pub max_credential_id_length: Option<u64>,
+    /// How the key can be reached, like "usb" or "nfc"
+    pub transports: Vec<String>,
+    /// Signature algorithms the key supports, as COSE algorithm identifiers
+    pub algorithms: Vec<i64>,
+    /// The shortest PIN the key accepts, in Unicode code points
+    pub min_pin_length: u64,
+    /// The firmware version, in the key's own numbering
+    pub firmware_version: Option<u64>,
+    /// How many more discoverable credentials fit on the key, if it says
+    pub remaining_discoverable_credentials: Option<u64>,
+}
+
+/// Sends `command` with its parameters, if it takes any, and returns the decoded response.
+/// `touch` is called when the key waits for the user to touch it.
+pub fn call(
+    device: &mut dyn Device,
+    command: u8,
+    parameters: Option<Value>,
+    touch: &dyn Fn(),
+) -> anyhow::Result<Value> {
+    let mut message = vec![command];
+    if let Some(parameters) = parameters {
+        ciborium::into_writer(&parameters, &mut message).context("Could not encode request")?;
+    }
+    let response = device.ctap2(&message, touch)?;
+    let (&status, body) = response
+        .split_first()
+        .context("Key sent an empty CTAP2 response")?;
+    if status != 0 {
+        return Err(CtapError(status).into());
+    }
+    if body.is_empty() {
+        return Ok(Value::Null);
+    }
+    ciborium::from_reader(body).context("Key sent an invalid CTAP2 response")
+}
+
+/// Asks the key what it supports
+pub fn get_info(device: &mut dyn Device) -> anyhow::Result<Info> {
+    let response = call(device, GET_INFO, None, &|| {})?;
+    let options = get(&response, 4)
+        .and_then(Value::as_map)
+        .map(|options| {
+            options
+                .iter()
+                .filter_map(|(name, value)| Some((name.as_text()?.to_string(), value.as_bool()?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let texts = |key: i64| -> Vec<String> {
+        get(&response, key)
+            .and_then(Value::as_array)
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|value| Some(value.as_text()?.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    Ok(Info {
+        versions: texts(1),
+        extensions: texts(2),
+        aaguid: get(&response, 3)
+            .and_then(Value::as_bytes)
+            .map(|aaguid| format_aaguid(aaguid))
+            .unwrap_or_default(),
+        options,
+        max_msg_size: get_unsigned(&response, 5),
+        pin_protocols: get(&response, 6)
+            .and_then(Value::as_array)
+            .map(|protocols| {
+                protocols
+                    .iter()
+                    .filter_map(|protocol| u64::try_from(protocol.as_integer()?).ok())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        max_credential_count_in_list: get_unsigned(&response, 7),
+        max_credential_id_length: get_unsigned(&response, 8),
+        transports: texts(9),
+        algorithms: get(&response, 0x0a)
+            .and_then(Value::as_array)
+            .map(|algorithms| {
+                algorithms
+                    .iter()
+                    .filter_map(|parameters| get_text(parameters, "alg")?.as_integer())
+                    .filter_map(|algorithm| i64::try_from(algorithm).ok())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        // CTAP 2.0 keys don't say, and always accept four
+        min_pin_length: get_unsigned(&response, 0x0d).unwrap_or(4),
+        firmware_version: get_unsigned(&response, 0x0e),
+        remaining_discoverable_credentials: get_unsigned(&response, 0x14),
+    })
+}
+
+/// Makes a credential for `rp_id` that isn't stored on the key, with `client_data_hash` as the
+/// challenge the attestation signs. Keys with a PIN only make credentials for requests
+/// authenticated with a PIN `token`.
+pub fn make_credential(
+    device: &mut dyn Device,
+    client_data_hash: &[u8; 32],
+    rp_id: &str,
+    user_id: &[u8],
+    token: Option<&PinToken>,
+    touch: &dyn Fn(),
+) -> anyhow::Result<Attestation> {
+    let mut parameters = vec![
+        (1, Value::Bytes(client_data_hash.to_vec())),
+        (2, Value::Map(vec![text_entry("id", rp_id)])),
+        (
+            3,
+            Value::Map(vec![
+                (Value::from("id"), Value::Bytes(user_id.to_vec())),
+                text_entry("name", "solo2-gui"),
+            ]),
+        ),
+        (
+            4,
+            Value::Array(vec![Value::Map(vec![
+                (Value::from("alg"), Value::from(ES256)),
+                text_entry("type", "public-key"),
+            ])]),
+        ),
+    ];
+    if let Some(token) = token {
+        // pinAuth covers just the challenge
+        parameters.push((8, Value::Bytes(token.authenticate(client_data_hash))));
+        parameters.push((9, token.protocol()));
+    }
+    let parameters = Value::Map(
+        parameters
+            .into_iter()
+            .map(|(key, value)| (Value::from(key), value))
+            .collect(),
+    );
+    let response = call(device, MAKE_CREDENTIAL, Some(parameters), touch)?;
+    Ok(Attestation {
+        format: get(&response, 1)
+            .and_then(Value::as_text)
+            .context("Key sent no attestation format")?
+            .to_string(),
+        auth_data: get(&response, 2)
+            .and_then(Value::as_bytes)
+            .context("Key sent no authenticator data")?
+            .clone(),
+        statement: get(&response, 3)
+            .context("Key sent no attestation statement")?
+            .clone(),
+    })
+}
+
+/// Signs `client_data_hash` with the credential `credential_id` of `rp_id`. Naming the credential
+/// means the key doesn't look through the passkeys it stores.
+pub fn get_assertion(
+    device: &mut dyn Device,
+    client_data_hash: &[u8; 32],
+    rp_id: &str,
+    credential_id: &[u8],
+    touch: &dyn Fn(),
+) -> anyhow::Result<Assertion> {
+    let parameters = map([
+        (1, Value::from(rp_id)),
+        (2, Value::Bytes(client_data_hash.to_vec())),
+        (
+            3,
+            Value::Array(vec![Value::Map(vec![
+                (Value::from("id"), Value::Bytes(credential_id.to_vec())),
+                text_entry("type", "public-key"),
+            ])]),
+        ),
+    ]);
+    let response = call(device, GET_ASSERTION, Some(parameters), touch)?;
+    Ok(Assertion {
+        auth_data: get(&response, 2)
+            .and_then(Value::as_bytes)
+            .context("Key sent no authenticator data")?
+            .clone(),
+        signature: get(&response, 3)
+            .and_then(Value::as_bytes)
+            .context("Key sent no signature")?
+            .clone(),
+    })
+}
+
+/// Deletes every credential and the PIN. Keys only accept this shortly after they're plugged in,
+/// and wait for a touch, calling `touch`.
+pub fn reset(device: &mut dyn Device, touch: &dyn Fn()) -> anyhow::Result<()> {
+    call(device, RESET, None, touch)?;
+    Ok(())
+}
+
+/// The AAGUID in authenticator data with attested credential data, which names the key's model
+pub fn aaguid(auth_data: &[u8]) -> Option<[u8; 16]> {
+    // The RP ID hash, flags and signature counter come first
+    auth_data.get(37..53)?.try_into().ok()
+}
+
+/// The credential ID and COSE public key in authenticator data with attested credential data
+pub fn credential(auth_data: &[u8]) -> anyhow::Result<(Vec<u8>, Value)> {
+    // The AAGUID is followed by the length of the credential ID, the ID and the key
+    let length = auth_data
+        .get(53..55)
+        .context("Authenticator data holds no credential")?;
+    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+    let id = auth_data
+        .get(55..55 + length)
+        .context("Authenticator data is cut off")?;
+    // Extensions may follow the key, which reading one CBOR value leaves alone
+    let key = ciborium::from_reader(&auth_data[55 + length..])
+        .context("Authenticator data holds an invalid public key")?;
+    Ok((id.to_vec(), key))
+}
+
+/// The P-256 public key in a COSE key
+pub fn p256_key(cose: &Value) -> anyhow::Result<PublicKey> {
+    let coordinate = |key: i64| -> anyhow::Result<&[u8]> {
+        let bytes = get(cose, key)
+            .and_then(Value::as_bytes)
+            .context("Public key is missing a coordinate")?;
+        ensure!(bytes.len() == 32, "Public key isn't on P-256");
+        Ok(bytes)
+    };
+    let point = EncodedPoint::<p256::NistP256>::from_affine_coordinates(
+        coordinate(-2)?.into(),
+        coordinate(-3)?.into(),
+        false,
+    );
+    Option::<PublicKey>::from(PublicKey::from_encoded_point(&point))
+        .context("Public key isn't on P-256")
+}
+
+/// The name of a COSE algorithm identifier
+pub fn algorithm_name(algorithm: i64) -> String {
+    match algorithm {
+        ES256 => "ES256".to_string(),
+        -8 => "EdDSA".to_string(),
+        -35 => "ES384".to_string(),
+        -257 => "RS256".to_string(),
+        algorithm => format!("COSE algorithm {algorithm}"),
+    }
+}
+
+/// Formats an AAGUID the way UUIDs are written
+pub fn format_aaguid(aaguid: &[u8]) -> String {
+    let hex = hex::encode(aaguid);
+    if hex.len() != 32 {
+        return hex;
+    }
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// A CBOR map with integer keys, as requests use
+pub fn map<const N: usize>(entries: [(i64, Value); N]) -> Value {
+    Value::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (Value::from(key), value))
+            .collect(),
+    )
+}
+
+/// The value under an integer key of a response map
+pub fn get(map: &Value, key: i64) -> Option<&Value> {
+    map.as_map()?
+        .iter()
+        .find(|(entry, _)| {
+            entry
+                .as_integer()
+                .is_some_and(|entry| i128::from(entry) == key as i128)
+        })
+        .map(|(_, value)| value)
+}
+
+/// The unsigned integer under an integer key of a response map
+pub fn get_unsigned(map: &Value, key: i64) -> Option<u64> {
+    get(map, key)?
+        .as_integer()
+        .and_then(|value| u64::try_from(value).ok())
+}
+
+/// The value under a text key of a map, as statements and nested structures use
+pub fn get_text<'a>(map: &'a Value, key: &str) -> Option<&'a Value> {
+    map.as_map()?
+        .iter()
+        .find(|(entry, _)| entry.as_text() == Some(key))
+        .map(|(_, value)| value)
+}
+
+fn text_entry(key: &str, value: &str) -> (Value, Value) {
+    (Value::from(key), Value::from(value))
+}
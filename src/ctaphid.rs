@@ -0,0 +1,177 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Talks CTAPHID, the framing FIDO keys use over USB HID, through a hidraw node. Messages are split
+//! into 64 byte reports: an initialization packet with the command and length, then continuation
+//! packets with sequence numbers. Each client gets its own channel from the key first.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Size of a HID report of FIDO keys
+const REPORT_SIZE: usize = 64;
+/// Payload bytes in an initialization packet, after the channel, command and length
+const INIT_DATA: usize = REPORT_SIZE - 7;
+/// Payload bytes in a continuation packet, after the channel and sequence number
+const CONT_DATA: usize = REPORT_SIZE - 5;
+/// The channel used to ask for a channel of our own
+const BROADCAST_CHANNEL: u32 = 0xffff_ffff;
+/// Commands, with the bit that marks initialization packets set
+const INIT: u8 = 0x86;
+const CBOR: u8 = 0x90;
+const KEEPALIVE: u8 = 0xbb;
+const ERROR: u8 = 0xbf;
+/// Keepalive status of a key waiting for the user to touch it
+const STATUS_UPNEEDED: u8 = 2;
+/// How long to wait for a message. Keys send keepalives while they work or wait for a touch, so
+/// this only runs out when a key stopped answering.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A channel to a FIDO key.
+pub struct CtapHid {
+    file: File,
+    channel: u32,
+}
+
+impl CtapHid {
+    /// Opens the hidraw node at `path` and gets a channel from the key
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut hid = CtapHid {
+            file,
+            channel: BROADCAST_CHANNEL,
+        };
+        let mut nonce = [0; 8];
+        getrandom::fill(&mut nonce).map_err(io::Error::other)?;
+        let response = hid.transact(INIT, &nonce, &|| {})?;
+        // Other clients may be opening channels at the same time, and get answers with their nonce
+        if response.len() < 12 || response[..8] != nonce {
+            return Err(io::Error::other(
+                "Key answered CTAPHID_INIT with another nonce",
+            ));
+        }
+        hid.channel = u32::from_be_bytes(response[8..12].try_into().expect("Slice is 4 bytes"));
+        Ok(hid)
+    }
+    /// Sends a CTAP2 message and returns the response, which starts with the status byte.
+    /// `touch` is called once if the key says it waits for the user to touch it.
+    pub fn cbor(&mut self, message: &[u8], touch: &dyn Fn()) -> io::Result<Vec<u8>> {
+        self.transact(CBOR, message, touch)
+    }
+    /// Sends a request and waits for the response to it, skipping keepalives
+    fn transact(&mut self, command: u8, data: &[u8], touch: &dyn Fn()) -> io::Result<Vec<u8>> {
+        self.send(command, data)?;
+        // Keys repeat their keepalive every 100ms or so, but the user only needs telling once
+        let mut touch_requested = false;
+        loop {
+            let (response_command, response) = self.receive()?;
+            match response_command {
+                KEEPALIVE => {
+                    if response.first() == Some(&STATUS_UPNEEDED) && !touch_requested {
+                        touch_requested = true;
+                        touch();
+                    }
+                }
+                ERROR => {
+                    return Err(io::Error::other(format!(
+                        "CTAPHID error {:#04x}",
+                        response.first().copied().unwrap_or_default()
+                    )));
+                }
+                _ if response_command == command => return Ok(response),
+                _ => {
+                    return Err(io::Error::other(format!(
+                        "Key answered CTAPHID command {command:#04x} with {response_command:#04x}"
+                    )));
+                }
+            }
+        }
+    }
+    fn send(&mut self, command: u8, data: &[u8]) -> io::Result<()> {
+        let length = u16::try_from(data.len())
+            .map_err(|_| io::Error::other("CTAPHID message is too long"))?;
+        // hidraw wants the report ID first, which is 0 since FIDO keys don't number their reports
+        let mut packet = [0; REPORT_SIZE + 1];
+        packet[1..5].copy_from_slice(&self.channel.to_be_bytes());
+        packet[5] = command;
+        packet[6..8].copy_from_slice(&length.to_be_bytes());
+        let (first, mut rest) = data.split_at(data.len().min(INIT_DATA));
+        packet[8..8 + first.len()].copy_from_slice(first);
+        self.file.write_all(&packet)?;
+
+        let mut sequence = 0;
+        while !rest.is_empty() {
+            let (chunk, remaining) = rest.split_at(rest.len().min(CONT_DATA));
+            let mut packet = [0; REPORT_SIZE + 1];
+            packet[1..5].copy_from_slice(&self.channel.to_be_bytes());
+            packet[5] = sequence;
+            packet[6..6 + chunk.len()].copy_from_slice(chunk);
+            self.file.write_all(&packet)?;
+            sequence += 1;
+            rest = remaining;
+        }
+        Ok(())
+    }
+    /// Reads a whole message for our channel, returning its command and payload
+    fn receive(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let deadline = Instant::now() + RECEIVE_TIMEOUT;
+        let mut packet = [0; REPORT_SIZE];
+        loop {
+            self.read_packet(&mut packet, deadline)?;
+            if packet[..4] == self.channel.to_be_bytes() && packet[4] & 0x80 != 0 {
+                break;
+            }
+        }
+        let command = packet[4];
+        let length = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+        let mut data = packet[7..7 + length.min(INIT_DATA)].to_vec();
+
+        let mut sequence = 0;
+        while data.len() < length {
+            self.read_packet(&mut packet, deadline)?;
+            if packet[..4] != self.channel.to_be_bytes() {
+                continue;
+            }
+            if packet[4] != sequence {
+                return Err(io::Error::other("CTAPHID packet out of sequence"));
+            }
+            let take = (length - data.len()).min(CONT_DATA);
+            data.extend_from_slice(&packet[5..5 + take]);
+            sequence += 1;
+        }
+        Ok((command, data))
+    }
+    /// Reads one report, failing if none arrives before `deadline`
+    fn read_packet(&mut self, packet: &mut [u8; REPORT_SIZE], deadline: Instant) -> io::Result<()> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Key stopped answering over CTAPHID",
+                ));
+            }
+            let mut poll_fd = libc::pollfd {
+                fd: self.file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // Round up, so the last fraction of a millisecond isn't spent spinning
+            let timeout = i32::try_from(remaining.as_millis() + 1).unwrap_or(i32::MAX);
+            // SAFETY: poll_fd is a single valid pollfd that outlives the call
+            let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout) };
+            if ready < 0 {
+                let why = io::Error::last_os_error();
+                if why.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(why);
+            }
+            if ready > 0 {
+                return self.file.read_exact(packet);
+            }
+        }
+    }
+}
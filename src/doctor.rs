@@ -0,0 +1,228 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Checks the things that usually stop Linux setups from finding a Solo2: permissions on its
+//! hidraw nodes, pcscd, the readers pcscd sees, and other programs holding the card exclusively.
+
+use std::io;
+use std::path::Path;
+
+/// USB vendor ID of the Solo 2, shared with other pid.codes projects
+pub const VENDOR_ID: &str = "1209";
+/// USB product ID of the Solo 2
+pub const PRODUCT_ID: &str = "beee";
+/// Where pcscd listens unless PCSCLITE_CSOCK_NAME says otherwise
+const PCSCD_SOCKET: &str = "/run/pcscd/pcscd.comm";
+/// A udev rule giving the logged in user access to the Solo 2's hidraw nodes
+const UDEV_RULE: &str = r#"echo 'SUBSYSTEM=="hidraw", ATTRS{idVendor}=="1209", ATTRS{idProduct}=="beee", TAG+="uaccess"' | sudo tee /etc/udev/rules.d/70-solo2.rules
+sudo udevadm control --reload-rules && sudo udevadm trigger"#;
+
+/// The outcome of one check.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    /// What was checked
+    pub check: &'static str,
+    /// Whether everything is fine
+    pub ok: bool,
+    /// What was found, in plain language
+    pub explanation: String,
+    /// Commands that fix the problem, if there is one
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, explanation: impl Into<String>) -> Self {
+        Finding {
+            check,
+            ok: true,
+            explanation: explanation.into(),
+            fix: None,
+        }
+    }
+    fn problem(check: &'static str, explanation: impl Into<String>, fix: Option<&str>) -> Self {
+        Finding {
+            check,
+            ok: false,
+            explanation: explanation.into(),
+            fix: fix.map(str::to_string),
+        }
+    }
+}
+
+/// Runs every check. This blocks, so it shouldn't run on the interface's thread.
+pub fn run() -> Vec<Finding> {
+    let mut findings = vec![check_hidraw()];
+    let socket = check_pcscd_socket();
+    let socket_ok = socket.ok;
+    findings.push(socket);
+    if socket_ok {
+        findings.extend(check_readers());
+    }
+    findings
+}
+
+/// Whether the Solo 2's hidraw nodes exist and can be opened
+fn check_hidraw() -> Finding {
+    const CHECK: &str = "HID access";
+    let nodes = match solo2_hidraw_nodes() {
+        Ok(nodes) => nodes,
+        Err(why) => {
+            return Finding::problem(
+                CHECK,
+                format!("Could not ask udev for HID devices: {why}"),
+                None,
+            );
+        }
+    };
+    if nodes.is_empty() {
+        return Finding::problem(
+            CHECK,
+            "No Solo 2 HID interface is plugged in. If the key is plugged in, try another USB port or cable.",
+            None,
+        );
+    }
+    let denied: Vec<String> = nodes
+        .iter()
+        .filter(|node| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(node)
+                .is_err_and(|why| why.kind() == io::ErrorKind::PermissionDenied)
+        })
+        .map(|node| node.display().to_string())
+        .collect();
+    if denied.is_empty() {
+        Finding::ok(CHECK, "The Solo 2's HID interface can be opened.")
+    } else {
+        Finding::problem(
+            CHECK,
+            format!(
+                "The Solo 2 is plugged in, but you aren't allowed to open {}. A udev rule that gives the logged in user access fixes this. Unplug and replug the key after adding it.",
+                denied.join(", ")
+            ),
+            Some(UDEV_RULE),
+        )
+    }
+}
+
+/// Device nodes of the hidraw interfaces of plugged in Solo 2s
+fn solo2_hidraw_nodes() -> io::Result<Vec<std::path::PathBuf>> {
+    let mut enumerator = udev::Enumerator::new()?;
+    enumerator.match_subsystem("hidraw")?;
+    let mut nodes = vec![];
+    for device in enumerator.scan_devices()? {
+        let Some(usb) = device.parent_with_subsystem_devtype("usb", "usb_device")? else {
+            continue;
+        };
+        let is_solo2 = usb.attribute_value("idVendor") == Some(VENDOR_ID.as_ref())
+            && usb.attribute_value("idProduct") == Some(PRODUCT_ID.as_ref());
+        if let (true, Some(node)) = (is_solo2, device.devnode()) {
+            nodes.push(node.to_path_buf());
+        }
+    }
+    Ok(nodes)
+}
+
+/// Whether pcscd is there to talk to
+fn check_pcscd_socket() -> Finding {
+    const CHECK: &str = "pcscd";
+    const FIX: &str = "sudo systemctl enable --now pcscd.socket";
+    let socket = std::env::var("PCSCLITE_CSOCK_NAME").unwrap_or_else(|_| PCSCD_SOCKET.to_string());
+    if !Path::new(&socket).exists() {
+        return Finding::problem(
+            CHECK,
+            format!(
+                "pcscd, the service that talks to smart cards, isn't running: {socket} doesn't exist. Without it the Solo 2 can only be used over HID."
+            ),
+            Some(FIX),
+        );
+    }
+    match pcsc::Context::establish(pcsc::Scope::User) {
+        Ok(_) => Finding::ok(CHECK, "pcscd is running."),
+        Err(why) => Finding::problem(
+            CHECK,
+            format!("pcscd's socket exists, but pcscd doesn't answer: {why}"),
+            Some("sudo systemctl restart pcscd.socket pcscd.service"),
+        ),
+    }
+}
+
+/// Whether pcscd sees the Solo 2, and whether another program holds it exclusively
+fn check_readers() -> Vec<Finding> {
+    const CHECK: &str = "Smart card readers";
+    let readers = match pcsc::Context::establish(pcsc::Scope::User)
+        .and_then(|context| Ok((context.list_readers_owned()?, context)))
+    {
+        Ok(readers) => readers,
+        Err(pcsc::Error::NoReadersAvailable) => {
+            return vec![Finding::problem(
+                CHECK,
+                "pcscd doesn't see any readers. pcscd needs its CCID driver to see the Solo 2, which most distributions package separately.",
+                Some(
+                    "sudo apt install libccid  # Debian and Ubuntu\nsudo dnf install pcsc-lite-ccid  # Fedora\nsudo pacman -S ccid  # Arch",
+                ),
+            )];
+        }
+        Err(why) => {
+            return vec![Finding::problem(
+                CHECK,
+                format!("Could not list readers: {why}"),
+                None,
+            )];
+        }
+    };
+    let (readers, context) = readers;
+    let solo2_readers: Vec<_> = readers
+        .iter()
+        .filter(|reader| reader.to_string_lossy().contains("Solo"))
+        .collect();
+    if solo2_readers.is_empty() {
+        let names: Vec<_> = readers
+            .iter()
+            .map(|reader| reader.to_string_lossy().into_owned())
+            .collect();
+        return vec![Finding::problem(
+            CHECK,
+            format!(
+                "pcscd sees {}, but no Solo 2. The installed CCID driver may be too old to know about it.",
+                names.join(", ")
+            ),
+            None,
+        )];
+    }
+
+    let mut findings = vec![Finding::ok(
+        CHECK,
+        format!(
+            "pcscd sees {}.",
+            solo2_readers
+                .iter()
+                .map(|reader| reader.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    )];
+    for reader in solo2_readers {
+        const EXCLUSIVE: &str = "Exclusive access";
+        match context.connect(reader, pcsc::ShareMode::Shared, pcsc::Protocols::ANY) {
+            Ok(_) => findings.push(Finding::ok(
+                EXCLUSIVE,
+                "No other program is holding the Solo 2 for itself.",
+            )),
+            Err(pcsc::Error::SharingViolation) => findings.push(Finding::problem(
+                EXCLUSIVE,
+                format!(
+                    "Another program is holding {} for itself, so nothing else can talk to it. This is usually GnuPG's scdaemon, which can be told to share the card.",
+                    reader.to_string_lossy()
+                ),
+                Some("echo pcsc-shared >> ~/.gnupg/scdaemon.conf\ngpgconf --kill scdaemon"),
+            )),
+            Err(why) => findings.push(Finding::problem(
+                EXCLUSIVE,
+                format!("Could not connect to {}: {why}", reader.to_string_lossy()),
+                None,
+            )),
+        }
+    }
+    findings
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Errors from talking to Solo2 devices, which are shown to the user instead of crashing the app.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Status word the OATH applet answers with when a credential that needs a touch wasn't touched
+const TOUCH_REQUIRED_STATUS: &str = "6985";
+
+/// Something that went wrong while talking to a device.
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// There is no device to talk to, for example because it was unplugged
+    NoDevice,
+    /// The worker that talks to the device has stopped
+    WorkerStopped,
+    /// The device didn't let us select one of its apps
+    SelectApp(&'static str, Arc<solo2::Error>),
+    /// An operation on the device failed
+    Device(&'static str, Arc<solo2::Error>),
+    /// The typed label or secret couldn't be turned into a credential
+    InvalidCredential(Arc<solo2::Error>),
+    /// The device didn't show up again after what it was asked to do, phrased to follow "after"
+    DidNotReturn(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Reports a problem the user doesn't need to act on, like a setting that couldn't be saved, to
+/// whoever reads the app's output. Errors the user has to see go to the error banner instead.
+pub fn log(what: impl fmt::Display, why: impl fmt::Display) {
+    eprintln!("error while {what}: {why}");
+}
+
+impl Error {
+    /// Whether the connection to the device broke, rather than the device refusing the request.
+    /// This happens after suspend and resume, when pcscd restarts, or when a HID write fails,
+    /// and reopening the device usually fixes it.
+    pub fn is_transport(&self) -> bool {
+        let (Error::SelectApp(_, why) | Error::Device(_, why)) = self else {
+            return false;
+        };
+        why.chain().any(|cause| {
+            cause.is::<std::io::Error>()
+                || cause.downcast_ref::<pcsc::Error>().is_some_and(|error| {
+                    matches!(
+                        error,
+                        pcsc::Error::ResetCard
+                            | pcsc::Error::RemovedCard
+                            | pcsc::Error::UnpoweredCard
+                            | pcsc::Error::ReaderUnavailable
+                            | pcsc::Error::UnknownReader
+                            | pcsc::Error::NoService
+                            | pcsc::Error::ServiceStopped
+                            | pcsc::Error::InvalidHandle
+                            | pcsc::Error::CommError
+                    )
+                })
+        })
+    }
+    /// Whether the device refused because the credential needs a touch and didn't get one,
+    /// rather than the request failing. The solo2 crate only passes the status word on in its
+    /// error message.
+    pub fn is_touch_required(&self) -> bool {
+        let Error::Device(_, why) = self else {
+            return false;
+        };
+        why.chain().any(|cause| {
+            cause
+                .to_string()
+                .to_ascii_uppercase()
+                .contains(TOUCH_REQUIRED_STATUS)
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoDevice => write!(f, "No solo2 device is connected."),
+            Error::WorkerStopped => write!(f, "Lost contact with the device worker."),
+            Error::SelectApp(app, why) => write!(f, "Could not enter the {app} app: {why}"),
+            Error::Device(operation, why) => write!(f, "Could not {operation}: {why}"),
+            Error::InvalidCredential(why) => write!(f, "Invalid credential: {why}"),
+            Error::DidNotReturn(operation) => {
+                write!(f, "The device didn't come back after {operation}.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Adds what we were doing to errors from the `solo2` crate.
+pub trait Context<T> {
+    /// Marks the error as coming from selecting the app named `app`
+    fn selecting(self, app: &'static str) -> Result<T>;
+    /// Marks the error as coming from `operation`, phrased to follow "Could not"
+    fn during(self, operation: &'static str) -> Result<T>;
+}
+
+impl<T> Context<T> for solo2::Result<T> {
+    fn selecting(self, app: &'static str) -> Result<T> {
+        self.map_err(|why| Error::SelectApp(app, Arc::new(why)))
+    }
+    fn during(self, operation: &'static str) -> Result<T> {
+        self.map_err(|why| Error::Device(operation, Arc::new(why)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_error(why: solo2::Error) -> Error {
+        Error::Device("calculate the code", Arc::new(why))
+    }
+
+    #[test]
+    fn tells_touch_refusals_from_broken_connections() {
+        let untouched = device_error(anyhow::anyhow!("card signaled error 6985"));
+        assert!(untouched.is_touch_required());
+        assert!(!untouched.is_transport());
+
+        let unplugged = device_error(std::io::Error::other("device was unplugged").into());
+        assert!(unplugged.is_transport());
+        assert!(!unplugged.is_touch_required());
+
+        let missing = device_error(anyhow::anyhow!("card signaled error 6A82"));
+        assert!(!missing.is_touch_required());
+        assert!(!Error::NoDevice.is_touch_required());
+    }
+}
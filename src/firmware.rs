@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Solo 2 firmware files and the stages of installing one. Firmware comes as NXP SB2.1 secure
+//! binaries. Only their format is checked here, not their signature: authenticity is left to the
+//! key's bootloader, which only refuses unsigned firmware once the key is locked.
+
+use anyhow::{Context, bail, ensure};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Where the `STMP` tag of the image header is
+const SIGNATURE_OFFSET: usize = 0x14;
+/// Where the major and minor versions of the format are
+const VERSION_OFFSET: usize = 0x18;
+/// Where the little endian header flags are
+const FLAGS_OFFSET: usize = 0x1a;
+/// Where the little endian image length in blocks is
+const IMAGE_BLOCKS_OFFSET: usize = 0x1c;
+/// Header flag of images with a certificate block and signature
+const SIGNED_FLAG: u16 = 0x08;
+/// Images are made of 16 byte cipher blocks
+const BLOCK_SIZE: usize = 16;
+
+/// Reads a firmware file and checks that it's an SB2.1 image claiming to be signed. Nothing here
+/// says who made it, that's left to the bootloader of a locked key.
+pub fn read(path: &Path) -> anyhow::Result<Arc<[u8]>> {
+    let data = std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    check_format(&data)?;
+    Ok(data.into())
+}
+
+/// Checks the image header, so the key isn't rebooted into its bootloader for a file it will
+/// obviously refuse. The signed flag only says a signature is there, not that it's valid.
+fn check_format(data: &[u8]) -> anyhow::Result<()> {
+    if data.len() < IMAGE_BLOCKS_OFFSET + 4 {
+        bail!("File is too short to be Solo 2 firmware");
+    }
+    ensure!(
+        &data[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 4] == b"STMP",
+        "File isn't Solo 2 firmware, which comes as .sb2 files"
+    );
+    let (major, minor) = (data[VERSION_OFFSET], data[VERSION_OFFSET + 1]);
+    ensure!(
+        (major, minor) == (2, 1),
+        "Firmware is in secure binary format {major}.{minor}, but Solo 2 keys need 2.1"
+    );
+    let flags = u16::from_le_bytes([data[FLAGS_OFFSET], data[FLAGS_OFFSET + 1]]);
+    ensure!(
+        flags & SIGNED_FLAG != 0,
+        "Firmware isn't marked as signed, so a locked key would refuse it"
+    );
+    let blocks = u32::from_le_bytes(
+        data[IMAGE_BLOCKS_OFFSET..IMAGE_BLOCKS_OFFSET + 4]
+            .try_into()
+            .expect("Slice is 4 bytes long"),
+    );
+    ensure!(
+        blocks as usize * BLOCK_SIZE == data.len(),
+        "Firmware file is truncated or has extra data: the header says {} bytes, the file has {}",
+        blocks as usize * BLOCK_SIZE,
+        data.len()
+    );
+    Ok(())
+}
+
+/// How far installing firmware has got.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// Asking the key to reboot into its bootloader
+    Rebooting,
+    /// Waiting for the bootloader to show up
+    WaitingForBootloader,
+    /// Writing the firmware, with the fraction written so far
+    Flashing(f32),
+    /// Waiting for the key to start its new firmware
+    Restarting,
+}
+
+impl Stage {
+    /// Whether stopping now leaves the old firmware in place. Once flashing starts, the key has
+    /// no working firmware until it finishes.
+    pub fn can_cancel(&self) -> bool {
+        matches!(self, Stage::Rebooting | Stage::WaitingForBootloader)
+    }
+    /// What is going on, for the progress dialog
+    pub fn description(&self) -> &'static str {
+        match self {
+            Stage::Rebooting => "Rebooting the key into its bootloader...",
+            Stage::WaitingForBootloader => "Waiting for the bootloader...",
+            Stage::Flashing(_) => "Writing firmware. Don't unplug the key.",
+            Stage::Restarting => "Waiting for the key to start the new firmware...",
+        }
+    }
+    /// Overall progress from 0 to 1
+    pub fn progress(&self) -> f32 {
+        match self {
+            Stage::Rebooting => 0.0,
+            Stage::WaitingForBootloader => 0.05,
+            Stage::Flashing(fraction) => 0.1 + 0.8 * fraction,
+            Stage::Restarting => 0.9,
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Watches for Solo2 devices being plugged in or removed, through udev for the USB and hidraw
+//! interfaces and through PC/SC for smart card readers. Suspend and resume are treated like the
+//! devices being removed and plugged back in, since connections don't survive them.
+
+use crate::doctor::{PRODUCT_ID, VENDOR_ID};
+use crate::error;
+use cosmic::iced_futures;
+use futures_util::{SinkExt, Stream, StreamExt};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long to wait for a device to finish enumerating before the device list is refreshed
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+/// How long to wait before talking to pcscd again after it went away, doubling up to the maximum
+const PCSC_RETRY_MIN: Duration = Duration::from_secs(1);
+const PCSC_RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// Something happened to the connected devices.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Event {
+    /// A device or reader went away, so anything read from it may be stale
+    Removed,
+    /// Devices were added or removed and have settled, so the device list should be refreshed
+    Changed,
+}
+
+/// A stream of hot-plug events, for use with `Subscription::run`.
+pub fn stream() -> impl Stream<Item = Event> {
+    iced_futures::stream::channel(1, |mut emitter| async move {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let udev_sender = sender.clone();
+        std::thread::spawn(move || watch_udev(udev_sender));
+        tokio::spawn(watch_logind(sender.clone()));
+        std::thread::spawn(move || watch_pcsc(sender));
+
+        while let Some(event) = receiver.recv().await {
+            // Let the app invalidate codes straight away, before the device list settles
+            if event == Event::Removed {
+                _ = emitter.send(Event::Removed).await;
+            }
+            tokio::time::sleep(SETTLE_TIME).await;
+            // A single plug or unplug causes a burst of events, which only need one refresh
+            while receiver.try_recv().is_ok() {}
+            _ = emitter.send(Event::Changed).await;
+        }
+    })
+}
+
+/// Forwards udev add and remove events for Solo 2 USB and hidraw devices until the receiver goes away
+fn watch_udev(sender: mpsc::UnboundedSender<Event>) {
+    // The udev socket can't be shared between threads, so it gets a runtime of its own
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(why) => {
+            error::log("starting udev watcher", why);
+            return;
+        }
+    };
+    runtime.block_on(async move {
+        let socket = match udev::MonitorBuilder::new()
+            .and_then(|builder| builder.match_subsystem("usb"))
+            .and_then(|builder| builder.match_subsystem("hidraw"))
+            .and_then(|builder| builder.listen())
+            .and_then(tokio::io::unix::AsyncFd::new)
+        {
+            Ok(socket) => socket,
+            Err(why) => {
+                error::log("watching udev for devices", why);
+                return;
+            }
+        };
+
+        loop {
+            let Ok(mut guard) = socket.readable().await else {
+                return;
+            };
+            for udev_event in guard.get_inner().iter() {
+                let event = match udev_event.event_type() {
+                    udev::EventType::Add => Event::Changed,
+                    udev::EventType::Remove => Event::Removed,
+                    _ => continue,
+                };
+                if !is_solo2(&udev_event) {
+                    continue;
+                }
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+            guard.clear_ready();
+        }
+    });
+}
+
+/// Whether a USB or hidraw device belongs to a Solo 2, by the same IDs the doctor looks for
+fn is_solo2(device: &udev::Device) -> bool {
+    // Removed devices have no attributes left to read, but USB devices still carry their IDs in
+    // the PRODUCT property, as vendor/product/version in hex without leading zeros
+    let product = |device: &udev::Device| {
+        device
+            .property_value("PRODUCT")
+            .and_then(|product| product.to_str())
+            .is_some_and(|product| product.starts_with(&format!("{VENDOR_ID}/{PRODUCT_ID}/")))
+    };
+    let attributes = |device: &udev::Device| {
+        device.attribute_value("idVendor") == Some(VENDOR_ID.as_ref())
+            && device.attribute_value("idProduct") == Some(PRODUCT_ID.as_ref())
+    };
+    if product(device) || attributes(device) {
+        return true;
+    }
+    // hidraw nodes get their IDs from the USB device they're part of
+    match device.parent_with_subsystem_devtype("usb", "usb_device") {
+        Ok(Some(usb)) => product(&usb) || attributes(&usb),
+        _ => false,
+    }
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait Login1Manager {
+    /// Sent with `start` set before the system suspends, and with it unset after it resumes
+    #[zbus(signal)]
+    fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+}
+
+/// Forwards suspend and resume from logind until the receiver goes away
+async fn watch_logind(sender: mpsc::UnboundedSender<Event>) {
+    let signals = async {
+        let connection = zbus::Connection::system().await?;
+        Login1ManagerProxy::new(&connection)
+            .await?
+            .receive_prepare_for_sleep()
+            .await
+    };
+    let mut signals = match signals.await {
+        Ok(signals) => signals,
+        Err(why) => {
+            error::log("watching logind for suspend", why);
+            return;
+        }
+    };
+
+    while let Some(signal) = signals.next().await {
+        let Ok(args) = signal.args() else {
+            continue;
+        };
+        // Connections opened before suspending are stale after resuming
+        let event = if args.start {
+            Event::Removed
+        } else {
+            Event::Changed
+        };
+        if sender.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+/// Forwards PC/SC reader and card changes until the receiver goes away, talking to pcscd again
+/// whenever it goes away, like when it's restarted or stops after being idle
+fn watch_pcsc(sender: mpsc::UnboundedSender<Event>) {
+    let mut retry = PCSC_RETRY_MIN;
+    // Whatever pcscd sees when it's first talked to is already known to the app
+    let mut report_first_change = false;
+    loop {
+        let mut watching = false;
+        let why = match watch_pcsc_context(&sender, report_first_change, &mut watching) {
+            Ok(()) => return,
+            Err(why) => why,
+        };
+        if watching {
+            // Cards can't be talked to without pcscd, and readers may differ once it's back
+            retry = PCSC_RETRY_MIN;
+            if sender.send(Event::Removed).is_err() {
+                return;
+            }
+        }
+        report_first_change = true;
+        error::log(
+            format_args!("watching PC/SC readers, retrying in {retry:?}"),
+            why,
+        );
+        std::thread::sleep(retry);
+        retry = (retry * 2).min(PCSC_RETRY_MAX);
+    }
+}
+
+/// Forwards PC/SC changes through one context until the receiver goes away or pcscd fails.
+/// Sets `watching` once pcscd has answered.
+fn watch_pcsc_context(
+    sender: &mpsc::UnboundedSender<Event>,
+    report_first_change: bool,
+    watching: &mut bool,
+) -> Result<(), pcsc::Error> {
+    let context = pcsc::Context::establish(pcsc::Scope::User)?;
+    // The notification reader reports readers being added and removed
+    let mut reader_states = vec![pcsc::ReaderState::new(
+        pcsc::PNP_NOTIFICATION(),
+        pcsc::State::UNAWARE,
+    )];
+    let mut first_change = true;
+
+    loop {
+        reader_states.retain(|state| {
+            !state
+                .event_state()
+                .intersects(pcsc::State::UNKNOWN | pcsc::State::IGNORE)
+        });
+        let readers = match context.list_readers_owned() {
+            Ok(readers) => readers,
+            // Not an error, just nothing plugged in yet
+            Err(pcsc::Error::NoReadersAvailable) => vec![],
+            Err(why) => return Err(why),
+        };
+        for reader in readers {
+            if !reader_states
+                .iter()
+                .any(|state| state.name() == reader.as_c_str())
+            {
+                reader_states.push(pcsc::ReaderState::new(reader, pcsc::State::UNAWARE));
+            }
+        }
+        for state in reader_states.iter_mut() {
+            state.sync_current_state();
+        }
+
+        context.get_status_change(None, &mut reader_states)?;
+        *watching = true;
+        // The first status change only reports the state everything was already in
+        if first_change {
+            first_change = false;
+            if report_first_change && sender.send(Event::Changed).is_err() {
+                return Ok(());
+            }
+            continue;
+        }
+
+        let changed = reader_states
+            .iter()
+            .filter(|state| state.event_state().contains(pcsc::State::CHANGED));
+        for state in changed {
+            let event = if state
+                .event_state()
+                .intersects(pcsc::State::EMPTY | pcsc::State::UNKNOWN | pcsc::State::UNAVAILABLE)
+            {
+                Event::Removed
+            } else {
+                Event::Changed
+            };
+            if sender.send(event).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 //! Provides localization support for this crate.
 
+use crate::error;
 use i18n_embed::{
     DefaultLocalizer, LanguageLoader, Localizer,
     fluent::{FluentLanguageLoader, fluent_language_loader},
@@ -13,7 +14,7 @@ use std::sync::LazyLock;
 /// Applies the requested language(s) to requested translations from the `fl!()` macro.
 pub fn init(requested_languages: &[LanguageIdentifier]) {
     if let Err(why) = localizer().select(requested_languages) {
-        eprintln!("error while loading fluent localizations: {why}");
+        error::log("loading fluent localizations", why);
     }
 }
 
@@ -37,7 +38,6 @@ pub static LANGUAGE_LOADER: LazyLock<FluentLanguageLoader> = LazyLock::new(|| {
     loader
 });
 
-
 /// Request a localized string by ID from the i18n/ directory.
 #[macro_export]
 macro_rules! fl {
@@ -49,4 +49,3 @@ macro_rules! fl {
         i18n_embed_fl::fl!($crate::i18n::LANGUAGE_LOADER, $message_id, $($args), *)
     }};
 }
-
@@ -1,10 +1,48 @@
 // SPDX-License-Identifier: AGPL-3.0
 
 mod app;
+mod backend;
+mod calculate;
+mod catalogue;
+mod client_pin;
+mod clock;
 mod config;
+mod ctap2;
+mod ctaphid;
+mod doctor;
+mod error;
+mod firmware;
+mod hotplug;
 mod i18n;
+mod oath;
+mod passkeys;
+mod reset;
+mod self_test;
+mod simulated;
+mod usb;
+#[cfg(feature = "virtual-card")]
+mod virtual_card;
+mod worker;
 
 fn main() -> cosmic::iced::Result {
+    // Serve a virtual card to vpcd instead of starting the interface when started with
+    // --virtual-card, optionally followed by vpcd's address. Only builds with the virtual-card
+    // feature have the card.
+    #[cfg(feature = "virtual-card")]
+    {
+        let mut args = std::env::args().skip_while(|arg| arg != "--virtual-card");
+        if args.next().is_some() {
+            let address = args
+                .next()
+                .unwrap_or_else(|| virtual_card::DEFAULT_ADDRESS.to_string());
+            if let Err(why) = virtual_card::serve(&address) {
+                error::log("serving the virtual card", why);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+    }
+
     // Get the system's preferred languages.
     let requested_languages = i18n_embed::DesktopLanguageRequester::requested_languages();
 
@@ -18,6 +56,16 @@ fn main() -> cosmic::iced::Result {
             .min_height(180.0),
     );
 
-    // Starts the application's event loop with `()` as the application's flags.
-    cosmic::app::run::<app::AppModel>(settings, ())
+    // Talk to simulated devices instead of real ones when started with --demo,
+    // and freeze the clock at the unix time following --time.
+    let flags = app::Flags {
+        demo: std::env::args().skip(1).any(|arg| arg == "--demo"),
+        time: std::env::args()
+            .skip_while(|arg| arg != "--time")
+            .nth(1)
+            .and_then(|time| time.parse().ok()),
+    };
+
+    // Starts the application's event loop with the parsed flags.
+    cosmic::app::run::<app::AppModel>(settings, flags)
 }
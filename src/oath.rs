@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Lists OATH credentials along with their type. The solo2 crate's list only returns labels, but
+//! the app has to know which credentials are HOTP, since calculating their codes uses them up.
+
+use crate::backend::ListedCredential;
+use anyhow::{Context, bail, ensure};
+use solo2::Select;
+use solo2::apps::{App, Oath};
+
+/// Instruction of the OATH applet's LIST command
+const LIST: u8 = 0xA1;
+const LIST_TAG: u8 = 0x72;
+/// The type is the high nibble of the first byte of a list entry, the algorithm the low one
+const TYPE_MASK: u8 = 0xf0;
+const HOTP: u8 = 0x10;
+
+/// Asks the device for the label and type of every credential on it
+pub fn list(solo2_device: &mut solo2::Solo2) -> anyhow::Result<Vec<ListedCredential>> {
+    let mut app = Oath::select(solo2_device)?;
+    let response = app
+        .transport()
+        .call_iso(0x00, LIST, 0x00, 0x00, &[])
+        .context("Device refused to list credentials")?;
+    parse_list(&response)
+}
+
+/// Reads the entries of a LIST response, which are each the type byte followed by the label
+fn parse_list(mut data: &[u8]) -> anyhow::Result<Vec<ListedCredential>> {
+    let mut credentials = vec![];
+    while let [tag, length, rest @ ..] = data {
+        ensure!(*tag == LIST_TAG, "Unexpected list entry tagged {tag:#04x}");
+        let Some((entry, remaining)) = rest.split_at_checked(*length as usize) else {
+            bail!("List entry is cut off");
+        };
+        let [kind, label @ ..] = entry else {
+            bail!("List entry is empty");
+        };
+        credentials.push(ListedCredential {
+            label: String::from_utf8(label.to_vec()).context("Label isn't UTF-8")?,
+            hotp: kind & TYPE_MASK == HOTP,
+        });
+        data = remaining;
+    }
+    ensure!(data.is_empty(), "List response is cut off");
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_types_from_list_entries() {
+        let response = [
+            &[LIST_TAG, 7, 0x21][..],
+            b"GitHub",
+            &[LIST_TAG, 4, 0x11],
+            b"VPN",
+        ]
+        .concat();
+        let credentials = parse_list(&response).unwrap();
+        assert_eq!(
+            credentials,
+            [
+                ListedCredential {
+                    label: "GitHub".to_string(),
+                    hotp: false,
+                },
+                ListedCredential {
+                    label: "VPN".to_string(),
+                    hotp: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_list_entries() {
+        assert!(parse_list(&[LIST_TAG, 7, 0x21, b'G']).is_err());
+        assert!(parse_list(&[LIST_TAG]).is_err());
+        assert!(parse_list(&[0x71, 1, 0x21]).is_err());
+    }
+
+    #[test]
+    fn parses_empty_list() {
+        assert!(parse_list(&[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Lists and deletes the discoverable credentials (passkeys) stored on a key, with the
+//! credentialManagement command of CTAP2. Every request that starts an enumeration or changes
+//! something needs a PIN token.
+
+use crate::backend::Device;
+use crate::client_pin::PinToken;
+use crate::ctap2::{self, CtapError};
+use anyhow::Context;
+use ciborium::Value;
+
+/// credentialManagement as standardised in CTAP 2.1, and the prototype command older firmware has
+const CREDENTIAL_MANAGEMENT: u8 = 0x0a;
+const CREDENTIAL_MANAGEMENT_PREVIEW: u8 = 0x41;
+/// credentialManagement subcommands
+const GET_CREDS_METADATA: u8 = 0x01;
+const ENUMERATE_RPS_BEGIN: u8 = 0x02;
+const ENUMERATE_RPS_NEXT: u8 = 0x03;
+const ENUMERATE_CREDENTIALS_BEGIN: u8 = 0x04;
+const ENUMERATE_CREDENTIALS_NEXT: u8 = 0x05;
+const DELETE_CREDENTIAL: u8 = 0x06;
+/// What keys answer when there is nothing to enumerate
+const NO_CREDENTIALS: CtapError = CtapError(0x2e);
+
+/// The passkeys on a key.
+#[derive(Debug, Clone, Default)]
+pub struct Passkeys {
+    /// Sites with passkeys on the key
+    pub relying_parties: Vec<RelyingParty>,
+    /// How many more passkeys fit on the key
+    pub remaining: u64,
+}
+
+/// A site with passkeys on the key.
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    /// The site's domain
+    pub id: String,
+    /// The site's name, if the site gave one
+    pub name: Option<String>,
+    pub passkeys: Vec<Passkey>,
+}
+
+/// A passkey for one account.
+#[derive(Debug, Clone)]
+pub struct Passkey {
+    /// The credential ID, which is how the key finds the passkey
+    pub id: Vec<u8>,
+    /// The account name, like an email address
+    pub user_name: Option<String>,
+    /// The name the site shows for the account
+    pub display_name: Option<String>,
+    /// The site's ID for the account, as hex
+    pub user_id: String,
+    /// The signature algorithm of the passkey, as a COSE algorithm identifier
+    pub algorithm: Option<i64>,
+    /// The credProtect level the site made the passkey with, from 1 to 3
+    pub protection: Option<u64>,
+}
+
+impl Passkey {
+    /// A name for the account, for lists and confirmations
+    pub fn account(&self) -> String {
+        self.display_name
+            .iter()
+            .chain(&self.user_name)
+            .next()
+            .cloned()
+            .unwrap_or_else(|| format!("User {}", self.user_id))
+    }
+    /// What the credProtect level means
+    pub fn protection(&self) -> &'static str {
+        match self.protection {
+            Some(3) => "Only usable after entering the PIN",
+            Some(2) => "Only usable by sites that already know it, unless the PIN is entered",
+            _ => "Usable with a touch",
+        }
+    }
+    /// What the signature algorithm is called
+    pub fn algorithm(&self) -> String {
+        match self.algorithm {
+            Some(algorithm) => ctap2::algorithm_name(algorithm),
+            None => "unknown algorithm".to_string(),
+        }
+    }
+}
+
+/// Lists every passkey on the key, grouped by site
+pub fn list(device: &mut dyn Device, token: &PinToken) -> anyhow::Result<Passkeys> {
+    let command = command(device)?;
+    let metadata = call(device, command, token, GET_CREDS_METADATA, None)?;
+    let mut passkeys = Passkeys {
+        relying_parties: vec![],
+        remaining: ctap2::get_unsigned(&metadata, 2).unwrap_or_default(),
+    };
+    if ctap2::get_unsigned(&metadata, 1) == Some(0) {
+        return Ok(passkeys);
+    }
+
+    let first = match call(device, command, token, ENUMERATE_RPS_BEGIN, None) {
+        Err(why) if why.downcast_ref() == Some(&NO_CREDENTIALS) => return Ok(passkeys),
+        result => result?,
+    };
+    let total = ctap2::get_unsigned(&first, 5).unwrap_or(1);
+    let mut responses = vec![first];
+    for _ in 1..total {
+        responses.push(call_next(device, command, ENUMERATE_RPS_NEXT)?);
+    }
+    for response in responses {
+        let rp = ctap2::get(&response, 3).context("Key sent no relying party")?;
+        let id_hash = ctap2::get(&response, 4)
+            .and_then(Value::as_bytes)
+            .context("Key sent no relying party ID hash")?;
+        passkeys.relying_parties.push(RelyingParty {
+            id: text(rp, "id").unwrap_or_else(|| hex::encode(id_hash)),
+            name: text(rp, "name"),
+            passkeys: credentials(device, command, token, id_hash)?,
+        });
+    }
+    Ok(passkeys)
+}
+
+/// Deletes the passkey with credential ID `id`
+pub fn delete(device: &mut dyn Device, token: &PinToken, id: &[u8]) -> anyhow::Result<()> {
+    let command = command(device)?;
+    call(
+        device,
+        command,
+        token,
+        DELETE_CREDENTIAL,
+        Some(ctap2::map([(2, descriptor(id))])),
+    )?;
+    Ok(())
+}
+
+/// The passkeys of the site with the given RP ID hash
+fn credentials(
+    device: &mut dyn Device,
+    command: u8,
+    token: &PinToken,
+    rp_id_hash: &[u8],
+) -> anyhow::Result<Vec<Passkey>> {
+    let first = call(
+        device,
+        command,
+        token,
+        ENUMERATE_CREDENTIALS_BEGIN,
+        Some(ctap2::map([(1, Value::Bytes(rp_id_hash.to_vec()))])),
+    )?;
+    let total = ctap2::get_unsigned(&first, 9).unwrap_or(1);
+    let mut responses = vec![first];
+    for _ in 1..total {
+        responses.push(call_next(device, command, ENUMERATE_CREDENTIALS_NEXT)?);
+    }
+    responses
+        .iter()
+        .map(|response| {
+            let user = ctap2::get(response, 6).context("Key sent no user")?;
+            let id = ctap2::get(response, 7)
+                .and_then(|descriptor| ctap2::get_text(descriptor, "id"))
+                .and_then(Value::as_bytes)
+                .context("Key sent no credential ID")?;
+            Ok(Passkey {
+                id: id.clone(),
+                user_name: text(user, "name"),
+                display_name: text(user, "displayName"),
+                user_id: ctap2::get_text(user, "id")
+                    .and_then(Value::as_bytes)
+                    .map(hex::encode)
+                    .unwrap_or_default(),
+                algorithm: ctap2::get(response, 8)
+                    .and_then(|key| ctap2::get(key, 3))
+                    .and_then(Value::as_integer)
+                    .and_then(|algorithm| i64::try_from(algorithm).ok()),
+                protection: ctap2::get_unsigned(response, 10),
+            })
+        })
+        .collect()
+}
+
+/// Which credentialManagement command the key understands
+fn command(device: &mut dyn Device) -> anyhow::Result<u8> {
+    let info = ctap2::get_info(device)?;
+    if info.options.contains_key("credMgmt") {
+        Ok(CREDENTIAL_MANAGEMENT)
+    } else if info.options.contains_key("credentialMgmtPreview") {
+        Ok(CREDENTIAL_MANAGEMENT_PREVIEW)
+    } else {
+        anyhow::bail!("The key can't list its passkeys")
+    }
+}
+
+/// Sends a subcommand that needs the PIN token, which authenticates the subcommand and its
+/// CBOR encoded parameters
+fn call(
+    device: &mut dyn Device,
+    command: u8,
+    token: &PinToken,
+    subcommand: u8,
+    parameters: Option<Value>,
+) -> anyhow::Result<Value> {
+    let mut authenticated = vec![subcommand];
+    if let Some(parameters) = &parameters {
+        ciborium::into_writer(parameters, &mut authenticated)
+            .context("Could not encode request")?;
+    }
+    let mut request = vec![(1, Value::from(subcommand))];
+    if let Some(parameters) = parameters {
+        request.push((2, parameters));
+    }
+    request.push((3, token.protocol()));
+    request.push((4, Value::Bytes(token.authenticate(&authenticated))));
+    let request = Value::Map(
+        request
+            .into_iter()
+            .map(|(key, value)| (Value::from(key), value))
+            .collect(),
+    );
+    ctap2::call(device, command, Some(request), &|| {})
+}
+
+/// Asks for the next item of an enumeration, which needs no PIN token
+fn call_next(device: &mut dyn Device, command: u8, subcommand: u8) -> anyhow::Result<Value> {
+    ctap2::call(
+        device,
+        command,
+        Some(ctap2::map([(1, Value::from(subcommand))])),
+        &|| {},
+    )
+}
+
+/// A PublicKeyCredentialDescriptor, which is how requests name a credential
+fn descriptor(id: &[u8]) -> Value {
+    Value::Map(vec![
+        (Value::from("id"), Value::Bytes(id.to_vec())),
+        (Value::from("type"), Value::from("public-key")),
+    ])
+}
+
+/// A text field of a map with text keys
+fn text(map: &Value, key: &str) -> Option<String> {
+    ctap2::get_text(map, key)
+        .and_then(Value::as_text)
+        .map(str::to_string)
+}
@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Resetting FIDO2, which deletes every passkey and the PIN. Keys only accept a reset in the first
+//! seconds after they're plugged in, and only with a touch, so nothing can wipe a key remotely.
+//! The key has to be replugged right before the reset is sent.
+
+use crate::ctap2::CtapError;
+
+/// How far the reset has got.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// Waiting for the key to be unplugged
+    Unplug,
+    /// Waiting for the key to be plugged back in
+    Replug,
+    /// The reset was sent, and the key waits for a touch to confirm it
+    Touch,
+}
+
+impl Stage {
+    /// Whether stopping now leaves the key as it was
+    pub fn can_cancel(&self) -> bool {
+        !matches!(self, Stage::Touch)
+    }
+    /// What the user needs to do, for the wizard
+    pub fn description(&self) -> &'static str {
+        match self {
+            Stage::Unplug => "Unplug the key.",
+            Stage::Replug => "Plug the key back in. The reset is sent as soon as it shows up.",
+            Stage::Touch => "Touch the key now to confirm the reset.",
+        }
+    }
+}
+
+/// Explains why a reset failed, with what to do about it where the CTAP2 error says
+pub fn explain(why: &anyhow::Error) -> String {
+    let Some(error) = why.downcast_ref::<CtapError>() else {
+        return format!("Could not reset FIDO2: {why:#}");
+    };
+    let advice = match error.0 {
+        0x30 => {
+            "The reset came too late after plugging the key in. Try again, and plug the key in firmly in one go."
+        }
+        0x27 => "The key refused to reset. Nothing was deleted.",
+        0x2f | 0x3a | 0x3b => "The key wasn't touched in time, so nothing was deleted.",
+        _ => "Nothing was reset.",
+    };
+    format!("{advice} ({error})")
+}
@@ -0,0 +1,236 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! A FIDO2 round trip without a website: the key registers a credential for a relying party that
+//! can't exist, then signs in with it, and both signatures are checked here. The credential isn't
+//! discoverable, so the key doesn't store it and the passkeys on it stay as they are.
+
+use crate::backend::Device;
+use crate::client_pin::PinToken;
+use crate::ctap2::{self, CtapError, ES256};
+use anyhow::{Context, anyhow, ensure};
+use ciborium::Value;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// Authenticator data flag of a key that checked the user is there, by a touch
+const USER_PRESENT: u8 = 0x01;
+/// DER encoding of a subject public key info with an uncompressed P-256 point, up to the point
+const P256_KEY_INFO: [u8; 26] = [
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// What the self-test found.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// The AAGUID of the new credential, which names the key's model
+    pub aaguid: String,
+    /// How making the credential went
+    pub register: Step,
+    /// How signing in with it went
+    pub authenticate: Step,
+    /// How the registration was signed, or why the signature doesn't check out
+    pub attestation: Result<&'static str, String>,
+    /// Whether the sign-in signature matched the new credential, or why not
+    pub assertion: Result<(), String>,
+    /// Whether the key said it saw a touch both times
+    pub user_present: bool,
+    /// The signature counter after registering and after signing in
+    pub counters: (u32, u32),
+}
+
+impl Report {
+    /// Whether the key behaved the way websites expect
+    pub fn passed(&self) -> bool {
+        self.attestation.is_ok()
+            && self.assertion.is_ok()
+            && self.user_present
+            && self.counters_ok()
+    }
+    /// Whether the signature counter went up, or isn't used at all. Sites may refuse keys whose
+    /// counter goes backwards, taking it for a cloned key.
+    pub fn counters_ok(&self) -> bool {
+        self.counters.1 > self.counters.0 || self.counters == (0, 0)
+    }
+}
+
+/// How long one request took, and when the key asked for a touch.
+#[derive(Debug, Clone, Copy)]
+pub struct Step {
+    pub duration: Duration,
+    /// How long after the request the key asked for a touch, if it did
+    pub touch: Option<Duration>,
+}
+
+impl Step {
+    /// The timings in words, for the report
+    pub fn description(&self) -> String {
+        let duration = self.duration.as_secs_f32();
+        match self.touch {
+            Some(touch) => format!(
+                "took {duration:.2} s, asked for a touch after {:.2} s and got it {:.2} s later",
+                touch.as_secs_f32(),
+                self.duration.saturating_sub(touch).as_secs_f32()
+            ),
+            None => format!("took {duration:.2} s without asking for a touch"),
+        }
+    }
+}
+
+/// Runs the self-test on `device`, which needs two touches. `touch` is called when the key waits
+/// for one. Keys with a PIN need its `token` to register. Errors mean a request failed, which is
+/// itself what a website would run into, apart from the key wanting its PIN.
+pub fn run(
+    device: &mut dyn Device,
+    token: Option<&PinToken>,
+    touch: &dyn Fn(),
+) -> anyhow::Result<Report> {
+    let (attestation, register, registration_challenge) = timed(touch, |challenge, touch| {
+        ctap2::make_credential(
+            device,
+            challenge,
+            ctap2::TEST_RP_ID,
+            b"self-test",
+            token,
+            touch,
+        )
+        .map_err(|why| match why.downcast_ref::<CtapError>() {
+            Some(CtapError(ctap2::PIN_REQUIRED)) => {
+                anyhow!("PIN required: this key has a PIN, so enter it to run the self-test")
+            }
+            // The key forgets its tokens when it's unplugged
+            Some(CtapError(ctap2::PIN_AUTH_INVALID)) => {
+                anyhow!("PIN required: the key no longer accepts the PIN entered before")
+            }
+            _ => why.context("Could not register"),
+        })
+    })?;
+    let (id, key) = ctap2::credential(&attestation.auth_data)?;
+    let key = VerifyingKey::from(ctap2::p256_key(&key).context("The new credential is unusable")?);
+
+    let (assertion, authenticate, challenge) = timed(touch, |challenge, touch| {
+        ctap2::get_assertion(device, challenge, ctap2::TEST_RP_ID, &id, touch)
+            .context("Could not sign in")
+    })?;
+    let assertion_check = (|| {
+        ensure!(
+            assertion.auth_data.get(..32) == Some(&Sha256::digest(ctap2::TEST_RP_ID)[..]),
+            "The key signed in to another relying party"
+        );
+        let signature =
+            Signature::from_der(&assertion.signature).context("The signature is malformed")?;
+        let mut signed = assertion.auth_data.clone();
+        signed.extend_from_slice(&challenge);
+        key.verify(&signed, &signature)
+            .context("The signature doesn't match the new credential")
+    })();
+
+    let flags = |auth_data: &[u8]| auth_data.get(32).copied().unwrap_or_default();
+    Ok(Report {
+        aaguid: ctap2::aaguid(&attestation.auth_data)
+            .map(|aaguid| ctap2::format_aaguid(&aaguid))
+            .unwrap_or_default(),
+        register,
+        authenticate,
+        attestation: verify_attestation(&attestation, &registration_challenge, &key)
+            .map_err(|why| format!("{why:#}")),
+        assertion: assertion_check.map_err(|why| format!("{why:#}")),
+        user_present: flags(&attestation.auth_data) & USER_PRESENT != 0
+            && flags(&assertion.auth_data) & USER_PRESENT != 0,
+        counters: (
+            counter(&attestation.auth_data),
+            counter(&assertion.auth_data),
+        ),
+    })
+}
+
+/// Checks the signature of a packed attestation over `client_data_hash`, made with the key in the
+/// leaf certificate or, for self attestation, with `credential_key`, the key of the new credential.
+/// Returns which of the two it was. Whether the certificate chains up to SoloKeys isn't checked.
+fn verify_attestation(
+    attestation: &ctap2::Attestation,
+    client_data_hash: &[u8; 32],
+    credential_key: &VerifyingKey,
+) -> anyhow::Result<&'static str> {
+    ensure!(
+        attestation.format == "packed",
+        "The key uses {} attestation, which can't be checked",
+        attestation.format
+    );
+    let statement = &attestation.statement;
+    let algorithm = ctap2::get_text(statement, "alg").and_then(Value::as_integer);
+    ensure!(
+        algorithm.is_some_and(|algorithm| i128::from(algorithm) == ES256 as i128),
+        "The key signed with an unsupported algorithm"
+    );
+    let signature = ctap2::get_text(statement, "sig")
+        .and_then(Value::as_bytes)
+        .context("The key sent no attestation signature")?;
+    let signature = Signature::from_der(signature).context("The signature is malformed")?;
+    let mut signed = attestation.auth_data.clone();
+    signed.extend_from_slice(client_data_hash);
+    let leaf = ctap2::get_text(statement, "x5c")
+        .and_then(Value::as_array)
+        .and_then(|certificates| certificates.first())
+        .and_then(Value::as_bytes);
+    match leaf {
+        Some(der) => {
+            certificate_key(der)?
+                .verify(&signed, &signature)
+                .context("The signature doesn't match the attestation certificate")?;
+            Ok("signed with the attestation certificate")
+        }
+        None => {
+            credential_key
+                .verify(&signed, &signature)
+                .context("The signature doesn't match")?;
+            Ok("self-signed with the new credential")
+        }
+    }
+}
+
+/// The P-256 key in a DER encoded certificate. Rather than parsing the whole certificate, this
+/// looks for the one subject public key info that can hold such a key, since nothing else about
+/// the certificate is checked.
+fn certificate_key(der: &[u8]) -> anyhow::Result<VerifyingKey> {
+    let start = der
+        .windows(P256_KEY_INFO.len())
+        .position(|window| window == P256_KEY_INFO)
+        .context("The attestation certificate doesn't hold a P-256 key")?
+        + P256_KEY_INFO.len();
+    let point = der
+        .get(start..start + 65)
+        .context("The attestation certificate is cut off")?;
+    VerifyingKey::from_sec1_bytes(point).context("The attestation certificate's key is invalid")
+}
+
+/// Runs one request over a fresh random challenge, timing it and noting when it asks for a touch
+fn timed<T>(
+    touch: &dyn Fn(),
+    request: impl FnOnce(&[u8; 32], &dyn Fn()) -> anyhow::Result<T>,
+) -> anyhow::Result<(T, Step, [u8; 32])> {
+    let mut challenge = [0; 32];
+    getrandom::fill(&mut challenge).context("Could not generate a challenge")?;
+    let start = Instant::now();
+    let touched = Cell::new(None);
+    let response = request(&challenge, &|| {
+        touched.set(Some(start.elapsed()));
+        touch();
+    })?;
+    let step = Step {
+        duration: start.elapsed(),
+        touch: touched.get(),
+    };
+    Ok((response, step, challenge))
+}
+
+/// The signature counter in authenticator data
+fn counter(auth_data: &[u8]) -> u32 {
+    auth_data
+        .get(33..37)
+        .map(|counter| u32::from_be_bytes(counter.try_into().expect("Slice is 4 bytes")))
+        .unwrap_or_default()
+}
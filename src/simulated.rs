@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! An in-memory Solo2 with real TOTP and HOTP maths, so the interface can be tried out and
+//! screenshotted without any hardware. Started with the `--demo` flag.
+
+use crate::app::TOTP_PERIOD;
+use crate::backend::{Backend, Bootloader, Credential, Device, ListedCredential, Transport};
+use crate::calculate::{self, Calculation};
+use crate::error::{self, Error};
+use crate::usb::Connection;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Number of digits of simulated codes
+const DIGITS: u8 = 6;
+/// How many bytes the simulated bootloader writes at a time
+const FLASH_CHUNK: usize = 4096;
+/// How long the simulated bootloader takes to write a chunk
+const FLASH_CHUNK_TIME: Duration = Duration::from_millis(20);
+/// The RFC 4226 test secret, base32 encoded
+pub(crate) const TEST_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+/// Simulated devices, which keep their credentials for as long as the app runs.
+pub struct SimulatedBackend {
+    devices: Vec<Arc<Mutex<SimulatedState>>>,
+}
+
+impl Default for SimulatedBackend {
+    /// A primary and a backup key with a few credentials each
+    fn default() -> Self {
+        let primary = SimulatedState::new("5eed0000000000000000000000000001", "2.0.0");
+        let backup = SimulatedState::new("5eed0000000000000000000000000002", "1.0.4");
+        let mut backend = SimulatedBackend {
+            devices: vec![Arc::new(Mutex::new(primary)), Arc::new(Mutex::new(backup))],
+        };
+        for device in backend.devices.iter_mut() {
+            let mut device = lock(device);
+            for label in ["GitHub", "Mail", "VPN"] {
+                device
+                    .register(&Credential {
+                        label: label.to_string(),
+                        secret: TEST_SECRET.to_string(),
+                        hotp: false,
+                    })
+                    .expect("Test secret is valid base32");
+            }
+        }
+        backend
+    }
+}
+
+impl Backend for SimulatedBackend {
+    fn devices(&mut self) -> Vec<Box<dyn Device>> {
+        self.devices
+            .iter()
+            .filter(|state| !lock(state).in_bootloader)
+            .map(|state| Box::new(SimulatedDevice(state.clone())) as Box<dyn Device>)
+            .collect()
+    }
+    fn bootloaders(&mut self) -> Vec<Box<dyn Bootloader>> {
+        self.devices
+            .iter()
+            .filter(|state| lock(state).in_bootloader)
+            .map(|state| Box::new(SimulatedDevice(state.clone())) as Box<dyn Bootloader>)
+            .collect()
+    }
+}
+
+/// A connection to a simulated device.
+struct SimulatedDevice(Arc<Mutex<SimulatedState>>);
+
+impl SimulatedDevice {
+    fn state(&self) -> MutexGuard<'_, SimulatedState> {
+        lock(&self.0)
+    }
+}
+
+fn lock(state: &Mutex<SimulatedState>) -> MutexGuard<'_, SimulatedState> {
+    // The state stays consistent even if a panic happened while it was held
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct SimulatedState {
+    uuid: String,
+    version: String,
+    transport: Transport,
+    /// Whether the device is in its bootloader instead of running firmware
+    in_bootloader: bool,
+    credentials: Vec<SimulatedCredential>,
+}
+
+struct SimulatedCredential {
+    label: String,
+    secret: Vec<u8>,
+    /// The next HOTP counter, None for TOTP credentials
+    counter: Option<u64>,
+}
+
+impl SimulatedState {
+    fn new(uuid: &str, version: &str) -> Self {
+        SimulatedState {
+            uuid: uuid.to_string(),
+            version: version.to_string(),
+            transport: Transport::Pcsc,
+            in_bootloader: false,
+            credentials: vec![],
+        }
+    }
+    fn register(&mut self, credential: &Credential) -> error::Result<()> {
+        // Be as lenient as authenticator apps are about padding and case
+        let secret = credential
+            .secret
+            .trim()
+            .trim_end_matches('=')
+            .to_uppercase();
+        let secret = data_encoding::BASE32_NOPAD
+            .decode(secret.as_bytes())
+            .map_err(|why| Error::InvalidCredential(anyhow::Error::from(why).into()))?;
+        // Registering an existing label overwrites it, like on a real device
+        self.credentials
+            .retain(|existing| existing.label != credential.label);
+        self.credentials.push(SimulatedCredential {
+            label: credential.label.clone(),
+            secret,
+            counter: credential.hotp.then_some(0),
+        });
+        Ok(())
+    }
+    fn credential(&mut self, label: &str) -> error::Result<&mut SimulatedCredential> {
+        self.credentials
+            .iter_mut()
+            .find(|credential| credential.label == label)
+            .ok_or_else(|| {
+                Error::Device(
+                    "find the credential",
+                    anyhow::anyhow!("no credential labelled \"{label}\"").into(),
+                )
+            })
+    }
+}
+
+impl Device for SimulatedDevice {
+    fn uuid(&self) -> String {
+        self.state().uuid.clone()
+    }
+    fn version(&self) -> String {
+        self.state().version.clone()
+    }
+    fn transports(&self) -> Vec<Transport> {
+        vec![Transport::Pcsc, Transport::Ctaphid]
+    }
+    fn transport(&self) -> Transport {
+        self.state().transport
+    }
+    fn set_transport(&mut self, transport: Transport) {
+        self.state().transport = transport;
+    }
+    fn connection(&self) -> Connection {
+        Connection {
+            usb_id: Some((0x1209, 0xbeee)),
+            hidraw: vec![],
+            reader: Some(format!("SoloKeys Solo 2 ({}) 00 00", self.state().uuid)),
+        }
+    }
+    fn responds_to(&mut self, aid: &[u8]) -> bool {
+        // Only the apps that are simulated
+        aid.starts_with(&[0xa0, 0x00, 0x00, 0x08, 0x47])
+            || aid.starts_with(&[0xa0, 0x00, 0x00, 0x05, 0x27])
+    }
+    fn locked(&mut self) -> error::Result<bool> {
+        Ok(true)
+    }
+    fn wink(&mut self) -> error::Result<()> {
+        Ok(())
+    }
+    fn reboot(&mut self) -> error::Result<()> {
+        Ok(())
+    }
+    fn boot_to_bootloader(&mut self) -> error::Result<()> {
+        self.state().in_bootloader = true;
+        Ok(())
+    }
+    fn list(&mut self) -> error::Result<Vec<ListedCredential>> {
+        Ok(self
+            .state()
+            .credentials
+            .iter()
+            .map(|credential| ListedCredential {
+                label: credential.label.clone(),
+                hotp: credential.counter.is_some(),
+            })
+            .collect())
+    }
+    fn register(&mut self, credential: &Credential) -> error::Result<()> {
+        self.state().register(credential)
+    }
+    fn delete(&mut self, label: &str) -> error::Result<()> {
+        let mut state = self.state();
+        state.credential(label)?;
+        state
+            .credentials
+            .retain(|credential| credential.label != label);
+        Ok(())
+    }
+    fn calculate(&mut self, label: &str, timestamp: u64) -> error::Result<String> {
+        let mut state = self.state();
+        let credential = state.credential(label)?;
+        let moving_factor = match credential.counter.as_mut() {
+            Some(counter) => {
+                *counter += 1;
+                *counter - 1
+            }
+            None => timestamp / TOTP_PERIOD,
+        };
+        let calculation = hmac_sha1(&credential.secret, &moving_factor.to_be_bytes());
+        Ok(calculation.code())
+    }
+    fn calculate_challenge(
+        &mut self,
+        label: &str,
+        challenge: &[u8],
+    ) -> anyhow::Result<Calculation> {
+        anyhow::ensure!(
+            challenge.len() <= calculate::MAX_CHALLENGE_BYTES,
+            "The challenge is {} bytes, but the OATH applet takes at most {}",
+            challenge.len(),
+            calculate::MAX_CHALLENGE_BYTES
+        );
+        let mut state = self.state();
+        let credential = state.credential(label)?;
+        Ok(hmac_sha1(&credential.secret, challenge))
+    }
+    fn ctap2(&mut self, _message: &[u8], _touch: &dyn Fn()) -> anyhow::Result<Vec<u8>> {
+        anyhow::bail!("Simulated keys don't speak FIDO2")
+    }
+}
+
+impl Bootloader for SimulatedDevice {
+    fn uuid(&self) -> String {
+        self.state().uuid.clone()
+    }
+    fn flash(&mut self, firmware: &[u8], progress: &dyn Fn(usize)) -> anyhow::Result<()> {
+        // Take about as long as a real key, so the progress bar can be seen moving
+        for written in (0..firmware.len()).step_by(FLASH_CHUNK) {
+            std::thread::sleep(FLASH_CHUNK_TIME);
+            progress((written + FLASH_CHUNK).min(firmware.len()));
+        }
+        Ok(())
+    }
+    fn reboot(&mut self) {
+        self.state().in_bootloader = false;
+    }
+}
+
+/// The HMAC-SHA1 of `message` and its RFC 4226 dynamic truncation
+pub(crate) fn hmac_sha1(secret: &[u8], message: &[u8]) -> Calculation {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(message);
+    let mut calculation = Calculation {
+        digits: DIGITS,
+        hmac: mac.finalize().into_bytes().to_vec(),
+        truncated: 0,
+    };
+    calculation.truncated = calculation
+        .truncate_hmac()
+        .expect("SHA1 HMACs are long enough to truncate");
+    calculation
+}
@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Finds how a device is plugged in: its USB IDs, hidraw nodes and PC/SC reader. Solo 2 keys use
+//! their UUID as USB serial number, which both udev and pcscd's reader names include.
+
+use crate::error;
+use std::fmt;
+
+/// How a device is plugged in, as far as the system can tell.
+#[derive(Debug, Clone, Default)]
+pub struct Connection {
+    /// USB vendor and product ID
+    pub usb_id: Option<(u16, u16)>,
+    /// Device nodes of the HID interface
+    pub hidraw: Vec<String>,
+    /// Name pcscd gives the smart card interface
+    pub reader: Option<String>,
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.usb_id {
+            Some((vendor, product)) => writeln!(f, "USB ID: {vendor:04x}:{product:04x}")?,
+            None => writeln!(f, "USB ID: unknown")?,
+        }
+        if self.hidraw.is_empty() {
+            writeln!(f, "HID device: none")?;
+        } else {
+            writeln!(f, "HID device: {}", self.hidraw.join(", "))?;
+        }
+        write!(
+            f,
+            "PC/SC reader: {}",
+            self.reader.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+/// Looks up the device whose USB serial number is `uuid`
+pub fn connection(uuid: &str) -> Connection {
+    let mut connection = Connection::default();
+    if let Err(why) = find_usb(uuid, &mut connection) {
+        error::log(format_args!("looking up USB device {uuid}"), why);
+    }
+    connection.reader = find_reader(uuid);
+    connection
+}
+
+/// Fills in the USB IDs and hidraw nodes from udev
+fn find_usb(uuid: &str, connection: &mut Connection) -> std::io::Result<()> {
+    let mut enumerator = udev::Enumerator::new()?;
+    enumerator.match_subsystem("hidraw")?;
+    for device in enumerator.scan_devices()? {
+        let Some(usb) = device.parent_with_subsystem_devtype("usb", "usb_device")? else {
+            continue;
+        };
+        let serial = usb.attribute_value("serial").unwrap_or_default();
+        if !serial.to_string_lossy().eq_ignore_ascii_case(uuid) {
+            continue;
+        }
+        let id = |attribute: &str| -> Option<u16> {
+            let value = usb.attribute_value(attribute)?.to_str()?;
+            u16::from_str_radix(value, 16).ok()
+        };
+        connection.usb_id = id("idVendor").zip(id("idProduct"));
+        if let Some(node) = device.devnode() {
+            connection.hidraw.push(node.display().to_string());
+        }
+    }
+    Ok(())
+}
+
+/// The name of the PC/SC reader that includes `uuid`
+fn find_reader(uuid: &str) -> Option<String> {
+    let context = pcsc::Context::establish(pcsc::Scope::User).ok()?;
+    let uuid = uuid.to_lowercase();
+    context
+        .list_readers_owned()
+        .ok()?
+        .iter()
+        .map(|reader| reader.to_string_lossy().into_owned())
+        .find(|reader| reader.to_lowercase().contains(&uuid))
+}
@@ -0,0 +1,454 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! A software Solo2 that plugs into a vpcd virtual reader from vsmartcard, so the PC/SC path
+//! of the app and of the solo2 crate can be exercised end to end without hardware. It answers
+//! the Admin and OATH APDUs the app sends, with the same maths as the simulated devices.
+//! Only built with the `virtual-card` feature, and started with the `--virtual-card` flag.
+
+use crate::simulated::{TEST_SECRET, hmac_sha1};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// Where vpcd listens for virtual cards by default
+pub const DEFAULT_ADDRESS: &str = "localhost:35963";
+/// UUID the virtual card reports, distinct from the simulated devices
+const UUID: [u8; 16] = [0x5e, 0xed, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x03];
+/// Firmware version the virtual card reports, encoded like the Admin applet does
+const VERSION: u32 = (2 << 22) | (1 << 6);
+/// ATR of a T=1 card without historical bytes
+const ATR: [u8; 5] = [0x3b, 0x80, 0x80, 0x01, 0x01];
+
+// vpcd control messages, which are one byte long
+const POWER_OFF: u8 = 0x00;
+const POWER_ON: u8 = 0x01;
+const RESET: u8 = 0x02;
+const GET_ATR: u8 = 0x04;
+
+const ADMIN_AID: &[u8] = &[0xa0, 0x00, 0x00, 0x08, 0x47, 0x00, 0x00, 0x00, 0x01];
+const OATH_AID: &[u8] = &[0xa0, 0x00, 0x00, 0x05, 0x27, 0x21, 0x01];
+
+const SELECT: u8 = 0xa4;
+// Admin applet instructions
+const WINK: u8 = 0x08;
+const VERSION_INS: u8 = 0x61;
+const UUID_INS: u8 = 0x62;
+const LOCKED: u8 = 0x63;
+// OATH applet instructions
+const PUT: u8 = 0x01;
+const DELETE: u8 = 0x02;
+const LIST: u8 = 0xa1;
+const CALCULATE: u8 = 0xa2;
+
+const NAME_TAG: u8 = 0x71;
+const LIST_TAG: u8 = 0x72;
+const KEY_TAG: u8 = 0x73;
+const CHALLENGE_TAG: u8 = 0x74;
+const FULL_RESPONSE_TAG: u8 = 0x75;
+const TRUNCATED_RESPONSE_TAG: u8 = 0x76;
+const VERSION_TAG: u8 = 0x79;
+const IMF_TAG: u8 = 0x7a;
+
+/// OATH type nibble of HOTP credentials
+const HOTP: u8 = 0x10;
+/// OATH algorithm nibble of HMAC-SHA1, the only one the virtual card supports
+const SHA1: u8 = 0x01;
+
+const SW_OK: u16 = 0x9000;
+const SW_WRONG_LENGTH: u16 = 0x6700;
+const SW_WRONG_DATA: u16 = 0x6a80;
+const SW_NOT_FOUND: u16 = 0x6a82;
+const SW_INS_NOT_SUPPORTED: u16 = 0x6d00;
+const SW_CLA_NOT_SUPPORTED: u16 = 0x6e00;
+
+/// Connects to vpcd at `address` and answers APDUs until vpcd hangs up.
+pub fn serve(address: &str) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    eprintln!("virtual card connected to vpcd at {address}");
+    answer(stream)
+}
+
+/// Answers vpcd over `stream` until it hangs up. Shutting the stream down unplugs the card.
+fn answer(mut stream: TcpStream) -> io::Result<()> {
+    let mut card = VirtualCard::default();
+    loop {
+        let mut length = [0; 2];
+        match stream.read_exact(&mut length) {
+            Err(why) if why.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let mut message = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut message)?;
+
+        let response = match message.as_slice() {
+            [POWER_OFF] | [POWER_ON] | [RESET] => {
+                card.applet = None;
+                continue;
+            }
+            [GET_ATR] => ATR.to_vec(),
+            [_] => continue,
+            apdu => card.respond(apdu),
+        };
+        stream.write_all(&(response.len() as u16).to_be_bytes())?;
+        stream.write_all(&response)?;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Applet {
+    Admin,
+    Oath,
+}
+
+struct VirtualCredential {
+    label: Vec<u8>,
+    /// OATH type and algorithm, as sent in PUT
+    kind: u8,
+    digits: u8,
+    secret: Vec<u8>,
+    counter: u64,
+}
+
+struct VirtualCard {
+    applet: Option<Applet>,
+    credentials: Vec<VirtualCredential>,
+}
+
+impl Default for VirtualCard {
+    /// A card with the same credentials as the simulated devices
+    fn default() -> Self {
+        let secret = data_encoding::BASE32_NOPAD
+            .decode(TEST_SECRET.as_bytes())
+            .expect("Test secret is valid base32");
+        let credentials = ["GitHub", "Mail", "VPN"]
+            .into_iter()
+            .map(|label| VirtualCredential {
+                label: label.as_bytes().to_vec(),
+                kind: SHA1,
+                digits: 6,
+                secret: secret.clone(),
+                counter: 0,
+            })
+            .collect();
+        VirtualCard {
+            applet: None,
+            credentials,
+        }
+    }
+}
+
+impl VirtualCard {
+    /// Answers a command APDU with the response data followed by the status word
+    fn respond(&mut self, apdu: &[u8]) -> Vec<u8> {
+        let (mut data, status) = match self.handle(apdu) {
+            Ok(data) => (data, SW_OK),
+            Err(status) => (vec![], status),
+        };
+        data.extend(status.to_be_bytes());
+        data
+    }
+    fn handle(&mut self, apdu: &[u8]) -> Result<Vec<u8>, u16> {
+        let [cla, ins, p1, p2, rest @ ..] = apdu else {
+            return Err(SW_WRONG_LENGTH);
+        };
+        if *cla != 0x00 {
+            return Err(SW_CLA_NOT_SUPPORTED);
+        }
+        let data = command_data(rest)?;
+        match (*ins, self.applet) {
+            (SELECT, _) if *p1 == 0x04 => self.select(data),
+            (WINK, Some(Applet::Admin)) => Ok(vec![]),
+            (VERSION_INS, Some(Applet::Admin)) => Ok(VERSION.to_be_bytes().to_vec()),
+            (UUID_INS, Some(Applet::Admin)) => Ok(UUID.to_vec()),
+            (LOCKED, Some(Applet::Admin)) => Ok(vec![1]),
+            (PUT, Some(Applet::Oath)) => self.put(data),
+            (DELETE, Some(Applet::Oath)) => {
+                let label = find(data, NAME_TAG).ok_or(SW_WRONG_DATA)?;
+                let index = self.position(label)?;
+                self.credentials.remove(index);
+                Ok(vec![])
+            }
+            (LIST, Some(Applet::Oath)) => Ok(self
+                .credentials
+                .iter()
+                .flat_map(|credential| {
+                    let mut value = vec![credential.kind];
+                    value.extend_from_slice(&credential.label);
+                    tlv(LIST_TAG, &value)
+                })
+                .collect()),
+            (CALCULATE, Some(Applet::Oath)) => self.calculate(data, *p2 != 0x00),
+            _ => Err(SW_INS_NOT_SUPPORTED),
+        }
+    }
+    fn select(&mut self, aid: &[u8]) -> Result<Vec<u8>, u16> {
+        // Like real applets, accept AIDs that are truncated to a prefix
+        if !aid.is_empty() && ADMIN_AID.starts_with(aid) {
+            self.applet = Some(Applet::Admin);
+            Ok(vec![])
+        } else if !aid.is_empty() && OATH_AID.starts_with(aid) {
+            self.applet = Some(Applet::Oath);
+            Ok(tlv(VERSION_TAG, &[0x04, 0x04, 0x00]))
+        } else {
+            Err(SW_NOT_FOUND)
+        }
+    }
+    fn put(&mut self, data: &[u8]) -> Result<Vec<u8>, u16> {
+        let label = find(data, NAME_TAG).ok_or(SW_WRONG_DATA)?;
+        let [kind, digits, secret @ ..] = find(data, KEY_TAG).ok_or(SW_WRONG_DATA)? else {
+            return Err(SW_WRONG_DATA);
+        };
+        if kind & 0x0f != SHA1 {
+            return Err(SW_WRONG_DATA);
+        }
+        let counter = match find(data, IMF_TAG) {
+            Some(imf) => u32::from_be_bytes(imf.try_into().map_err(|_| SW_WRONG_DATA)?) as u64,
+            None => 0,
+        };
+        // Registering an existing label overwrites it
+        self.credentials
+            .retain(|credential| credential.label != label);
+        self.credentials.push(VirtualCredential {
+            label: label.to_vec(),
+            kind: *kind,
+            digits: *digits,
+            secret: secret.to_vec(),
+            counter,
+        });
+        Ok(vec![])
+    }
+    fn calculate(&mut self, data: &[u8], truncated: bool) -> Result<Vec<u8>, u16> {
+        let label = find(data, NAME_TAG).ok_or(SW_WRONG_DATA)?;
+        let challenge = find(data, CHALLENGE_TAG).unwrap_or_default();
+        let index = self.position(label)?;
+        let credential = &mut self.credentials[index];
+        // HOTP credentials ignore the challenge and use their counter instead
+        let moving_factor;
+        let message = if credential.kind & 0xf0 == HOTP {
+            moving_factor = credential.counter.to_be_bytes();
+            credential.counter += 1;
+            &moving_factor[..]
+        } else {
+            challenge
+        };
+        let calculation = hmac_sha1(&credential.secret, message);
+        let mut value = vec![credential.digits];
+        if truncated {
+            value.extend(calculation.truncated.to_be_bytes());
+            Ok(tlv(TRUNCATED_RESPONSE_TAG, &value))
+        } else {
+            value.extend(calculation.hmac);
+            Ok(tlv(FULL_RESPONSE_TAG, &value))
+        }
+    }
+    fn position(&self, label: &[u8]) -> Result<usize, u16> {
+        self.credentials
+            .iter()
+            .position(|credential| credential.label == label)
+            .ok_or(SW_NOT_FOUND)
+    }
+}
+
+/// The data field of a short or extended command APDU, without the header and Le
+fn command_data(body: &[u8]) -> Result<&[u8], u16> {
+    match body {
+        // Case 1, or case 2 with a short Le
+        [] | [_] => Ok(&[]),
+        // Case 2 or 4 with extended lengths
+        [0, high, low, rest @ ..] => {
+            let length = u16::from_be_bytes([*high, *low]) as usize;
+            if rest.is_empty() {
+                return Ok(&[]);
+            }
+            rest.get(..length).ok_or(SW_WRONG_LENGTH)
+        }
+        // Case 3 or 4 with a short Lc
+        [length, rest @ ..] => rest.get(..*length as usize).ok_or(SW_WRONG_LENGTH),
+    }
+}
+
+/// Encodes a short tag-length-value entry
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut entry = vec![tag, value.len() as u8];
+    entry.extend_from_slice(value);
+    entry
+}
+
+/// The value of the first short tag-length-value entry tagged `tag`
+fn find(mut data: &[u8], tag: u8) -> Option<&[u8]> {
+    while let [entry_tag, length, rest @ ..] = data {
+        let (value, remaining) = rest.split_at_checked(*length as usize)?;
+        if *entry_tag == tag {
+            return Some(value);
+        }
+        data = remaining;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    //! These go through pcscd, the solo2 crate and the worker, like the app does with a real
+    //! key. They need pcscd running with vpcd from vsmartcard, so they are ignored unless asked
+    //! for with `just test-virtual-card`, and fail when vpcd isn't there.
+
+    use super::*;
+    use crate::backend::Solo2Backend;
+    use crate::error::Error;
+    use crate::simulated::TEST_SECRET;
+    use crate::worker::tests::{CLOCK, Harness, labels};
+    use crate::worker::{Command, DeviceInfo, Reply};
+    use std::net::Shutdown;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    /// UUID the virtual card reports, as the worker formats it
+    const VIRTUAL_UUID: &str = "5eed0000000000000000000000000003";
+    /// How long pcscd gets to notice the virtual card
+    const PLUG_IN_TIME: Duration = Duration::from_secs(5);
+    /// vpcd has one slot, so tests take turns plugging the virtual card in
+    static VPCD: Mutex<()> = Mutex::new(());
+
+    /// The virtual card plugged into vpcd, unplugged when dropped.
+    struct Plugged {
+        stream: TcpStream,
+        card: Option<JoinHandle<io::Result<()>>>,
+        _vpcd: MutexGuard<'static, ()>,
+    }
+
+    impl Drop for Plugged {
+        fn drop(&mut self) {
+            _ = self.stream.shutdown(Shutdown::Both);
+            if let Some(card) = self.card.take() {
+                _ = card.join();
+            }
+        }
+    }
+
+    /// Plugs a fresh virtual card into vpcd and selects it in a worker over the solo2 crate
+    fn plug_in() -> (Plugged, Harness, DeviceInfo) {
+        // A test failing doesn't leave the slot in use, since its card was unplugged
+        let vpcd = VPCD.lock().unwrap_or_else(PoisonError::into_inner);
+        let stream = TcpStream::connect(DEFAULT_ADDRESS).unwrap_or_else(|why| {
+            panic!(
+                "vpcd isn't listening at {DEFAULT_ADDRESS} ({why}). These tests need pcscd running with vsmartcard's vpcd driver."
+            )
+        });
+        let card = thread::spawn({
+            let stream = stream.try_clone().expect("The stream can be cloned");
+            move || answer(stream)
+        });
+        let plugged = Plugged {
+            stream,
+            card: Some(card),
+            _vpcd: vpcd,
+        };
+
+        let mut worker = Harness::with_backend(Box::new(Solo2Backend));
+        let started = Instant::now();
+        loop {
+            let (uuids, device) = worker.select(VIRTUAL_UUID);
+            if uuids.iter().any(|uuid| uuid == VIRTUAL_UUID) {
+                let device = device.expect("The virtual card is selected");
+                assert_eq!(device.uuid, VIRTUAL_UUID);
+                return (plugged, worker, device);
+            }
+            assert!(
+                started.elapsed() < PLUG_IN_TIME,
+                "The virtual card connected to vpcd, but pcscd didn't show it"
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn codes(worker: &mut Harness, label: &str, count: u64) -> (Vec<String>, Option<Error>) {
+        let reply = worker.request(Command::Codes {
+            label: label.to_string(),
+            count,
+            clock: CLOCK,
+        });
+        let Reply::Codes { codes, error, .. } = reply else {
+            panic!("Unexpected reply {reply:?}");
+        };
+        (codes, error)
+    }
+
+    #[test]
+    #[ignore = "needs pcscd with vsmartcard's vpcd; run with `just test-virtual-card`"]
+    fn registers_calculates_and_deletes_totp_credentials() {
+        let (_card, mut worker, device) = plug_in();
+        assert_eq!(labels(&device), ["GitHub", "Mail", "VPN"]);
+        for credential in &device.totp_list {
+            assert_eq!(credential.code.as_deref(), Ok("287082"));
+        }
+
+        let reply = worker.request(Command::Register {
+            label: "Test".to_string(),
+            secret: TEST_SECRET.to_string(),
+            hotp: false,
+        });
+        assert!(matches!(reply, Reply::Registered { result: Ok(()), .. }));
+        let (_, device) = worker.select(VIRTUAL_UUID);
+        assert_eq!(labels(&device.unwrap()), ["GitHub", "Mail", "VPN", "Test"]);
+
+        // RFC 6238's SHA1 test vector at 59 seconds, cut down to 6 digits
+        let (codes, error) = codes(&mut worker, "Test", 1);
+        assert_eq!(codes, ["287082"]);
+        assert!(error.is_none());
+
+        let reply = worker.request(Command::Delete("Test".to_string()));
+        assert!(matches!(reply, Reply::Deleted { result: Ok(()), .. }));
+        let (_, device) = worker.select(VIRTUAL_UUID);
+        assert_eq!(labels(&device.unwrap()), ["GitHub", "Mail", "VPN"]);
+    }
+
+    #[test]
+    #[ignore = "needs pcscd with vsmartcard's vpcd; run with `just test-virtual-card`"]
+    fn refuses_credentials_that_are_not_there() {
+        let (_card, mut worker, _) = plug_in();
+        let (codes, error) = codes(&mut worker, "Missing", 1);
+        assert!(codes.is_empty());
+        assert!(matches!(error, Some(Error::Device(..))));
+        let reply = worker.request(Command::Delete("Missing".to_string()));
+        assert!(matches!(
+            reply,
+            Reply::Deleted {
+                result: Err(Error::Device(..)),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs pcscd with vsmartcard's vpcd; run with `just test-virtual-card`"]
+    fn lists_hotp_credentials_with_their_type_and_counts_up() {
+        let (_card, mut worker, _) = plug_in();
+        let reply = worker.request(Command::Register {
+            label: "Counter".to_string(),
+            secret: TEST_SECRET.to_string(),
+            hotp: true,
+        });
+        assert!(matches!(
+            reply,
+            Reply::Registered {
+                hotp: true,
+                result: Ok(()),
+                ..
+            }
+        ));
+
+        // The worker tells HOTP credentials apart by the type nibble of their LIST entry, and
+        // leaves their codes alone when listing
+        let (_, device) = worker.select(VIRTUAL_UUID);
+        let device = device.unwrap();
+        let counter = device.totp_list.last().unwrap();
+        assert_eq!(counter.label, "Counter");
+        assert!(counter.hotp);
+        assert_eq!(counter.code.as_deref(), Ok(""));
+
+        // RFC 4226's first two codes, whatever the time
+        let (codes, error) = codes(&mut worker, "Counter", 2);
+        assert_eq!(codes, ["755224", "287082"]);
+        assert!(error.is_none());
+    }
+}
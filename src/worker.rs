@@ -0,0 +1,1038 @@
+// SPDX-License-Identifier: AGPL-3.0
+
+//! Owns the connection to the selected Solo2 device on a thread of its own, so smart card and HID
+//! exchanges never freeze the interface. The app sends it commands and gets replies back as messages.
+
+use crate::app::TOTP_PERIOD;
+use crate::backend::{self, Backend, Bootloader, Credential, Device, Solo2Backend, Transport};
+use crate::calculate::Calculation;
+use crate::client_pin::{self, PinStatus, PinToken};
+use crate::clock::Clock;
+use crate::config::TransportPreference;
+use crate::ctap2;
+use crate::error::{self, Error};
+use crate::firmware::Stage;
+use crate::passkeys::{self, Passkeys};
+use crate::reset;
+use crate::self_test;
+use crate::simulated::SimulatedBackend;
+use crate::usb::Connection;
+use cosmic::iced_futures;
+use futures_util::{SinkExt, Stream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc as async_mpsc;
+
+/// How long a key takes to show up after rebooting, at most
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often to look for a rebooting key
+const REBOOT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long to wait for the user to unplug or replug a key before giving up on a FIDO2 reset
+const REPLUG_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Something for the worker to do with the device.
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Look for devices and load everything from the one with UUID `preferred`, or the first one found,
+    /// talking to it over the preferred transport.
+    /// Codes of HOTP credentials aren't calculated, since that would use them up.
+    Refresh {
+        preferred: String,
+        clock: Clock,
+        transport: TransportPreference,
+    },
+    /// Blink the device's LED
+    Wink,
+    /// Restart the selected device, into its bootloader if `bootloader` is set,
+    /// and wait for it to come back
+    Reboot { bootloader: bool },
+    /// Restart the device with UUID `uuid` from its bootloader into its firmware
+    LeaveBootloader(String),
+    /// Calculate `count` consecutive codes of a credential
+    Codes {
+        label: String,
+        count: u64,
+        clock: Clock,
+    },
+    /// Delete a credential
+    Delete(String),
+    /// Register a credential from a label and base32 secret
+    Register {
+        label: String,
+        secret: String,
+        hotp: bool,
+    },
+    /// Find the credentials that produced a code, skipping HOTP credentials
+    Verify { code: String, clock: Clock },
+    /// Calculate the HMAC of an arbitrary challenge
+    Calculate { label: String, challenge: Vec<u8> },
+    /// Install a firmware image whose format was checked on the selected device, unless `cancel` is set before
+    /// flashing starts
+    UpdateFirmware {
+        firmware: Arc<[u8]>,
+        cancel: Arc<AtomicBool>,
+    },
+    /// Install a firmware image whose format was checked on the device with UUID `uuid`, which is stuck in its bootloader
+    RecoverFirmware { uuid: String, firmware: Arc<[u8]> },
+    /// Find out whether the selected device has a FIDO2 PIN and how many tries are left
+    PinStatus,
+    /// Ask the selected device what it supports with authenticatorGetInfo
+    FidoInfo,
+    /// Set the FIDO2 PIN, or change it if `current` is given
+    SetPin {
+        current: Option<String>,
+        pin: String,
+    },
+    /// List the passkeys on the selected device, after exchanging `pin` for a PIN token. Without
+    /// a PIN, the token from the last time is used.
+    ListPasskeys { pin: Option<String> },
+    /// Delete the passkey with the given credential ID from the selected device
+    DeletePasskey(Vec<u8>),
+    /// Reset FIDO2 on the selected device once the user has replugged it, unless `cancel` is set
+    /// before the reset is sent
+    ResetFido { cancel: Arc<AtomicBool> },
+    /// Register with the selected device and sign in with it, checking both signatures. Keys with
+    /// a PIN only register with a PIN token, which `pin` is exchanged for first. Without a PIN,
+    /// the token from the last time is used, if there is one.
+    SelfTest { pin: Option<String> },
+}
+
+/// What a command does, so the interface can show which operations are still running.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    Refresh,
+    Wink,
+    Reboot,
+    Codes(String),
+    Delete(String),
+    Register,
+    Verify,
+    Calculate,
+    UpdateFirmware,
+    PinStatus,
+    FidoInfo,
+    SetPin,
+    ListPasskeys,
+    DeletePasskey(Vec<u8>),
+    ResetFido,
+    SelfTest,
+}
+
+impl Command {
+    /// Whether running the command twice has the same effect as running it once, so it can be
+    /// retried after reconnecting. Calculating codes and challenges can move HOTP counters.
+    fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Command::Refresh { .. } | Command::Wink | Command::Verify { .. }
+        )
+    }
+    pub fn operation(&self) -> Operation {
+        match self {
+            Command::Refresh { .. } => Operation::Refresh,
+            Command::Wink => Operation::Wink,
+            Command::Reboot { .. } | Command::LeaveBootloader(_) => Operation::Reboot,
+            Command::Codes { label, .. } => Operation::Codes(label.clone()),
+            Command::Delete(label) => Operation::Delete(label.clone()),
+            Command::Register { .. } => Operation::Register,
+            Command::Verify { .. } => Operation::Verify,
+            Command::Calculate { .. } => Operation::Calculate,
+            Command::UpdateFirmware { .. } | Command::RecoverFirmware { .. } => {
+                Operation::UpdateFirmware
+            }
+            Command::PinStatus => Operation::PinStatus,
+            Command::FidoInfo => Operation::FidoInfo,
+            Command::SetPin { .. } => Operation::SetPin,
+            Command::ListPasskeys { .. } => Operation::ListPasskeys,
+            Command::DeletePasskey(id) => Operation::DeletePasskey(id.clone()),
+            Command::ResetFido { .. } => Operation::ResetFido,
+            Command::SelfTest { .. } => Operation::SelfTest,
+        }
+    }
+}
+
+/// Everything the interface shows about the selected device.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub uuid: String,
+    pub version: String,
+    pub locked: bool,
+    /// The transport the device is talked to over
+    pub transport: String,
+    /// Why the preferred transport isn't used, if it isn't
+    pub transport_note: Option<String>,
+    /// Every transport the device can be talked to over
+    pub transports: String,
+    /// How the device is plugged in
+    pub connection: Connection,
+    /// Names of the applets that can be selected
+    pub applets: Vec<&'static str>,
+    /// The credentials and their current codes
+    pub totp_list: Vec<CredentialCode>,
+}
+
+/// A credential on the device and its current code.
+#[derive(Debug, Clone)]
+pub struct CredentialCode {
+    pub label: String,
+    /// Whether it's a counter based HOTP credential, as the device lists it
+    pub hotp: bool,
+    /// The current code, empty for HOTP credentials, or why it couldn't be calculated
+    pub code: Result<String, String>,
+}
+
+/// The outcome of a command.
+#[derive(Debug, Clone)]
+pub enum Reply {
+    /// UUIDs of all connected devices, and what was loaded from the selected one if there is one.
+    /// When no devices were found, also why PC/SC can't be used if it can't.
+    /// Devices stuck in their bootloader are listed separately by UUID in `recovery`.
+    Refreshed {
+        uuids: Vec<String>,
+        device: error::Result<Option<DeviceInfo>>,
+        pcsc_problem: Option<String>,
+        recovery: Vec<String>,
+    },
+    Winked(error::Result<()>),
+    Rebooted(error::Result<()>),
+    /// The codes that were calculated before any error, which still moved HOTP counters
+    Codes {
+        label: String,
+        codes: Vec<String>,
+        error: Option<Error>,
+    },
+    Deleted {
+        label: String,
+        result: error::Result<()>,
+    },
+    Registered {
+        label: String,
+        hotp: bool,
+        result: error::Result<()>,
+    },
+    /// Labels and window offsets (-1, 0 or 1) of the credentials that produced the code
+    Verified(error::Result<Vec<(String, i64)>>),
+    Calculated(Result<Calculation, String>),
+    /// The firmware version the device reports after the update
+    FirmwareUpdated(Result<String, String>),
+    PinStatus(Result<PinStatus, String>),
+    FidoInfo(Result<ctap2::Info, String>),
+    /// Whether the PIN was set, and whether it was changed rather than set for the first time
+    PinSet {
+        changed: bool,
+        result: Result<(), String>,
+    },
+    Passkeys(Result<Passkeys, String>),
+    PasskeyDeleted(Result<(), String>),
+    FidoReset(Result<(), String>),
+    /// What the self-test found, or the request that failed
+    SelfTest(Result<self_test::Report, String>),
+}
+
+impl Reply {
+    /// The error the command failed with, if the worker can tell
+    fn error(&self) -> Option<&Error> {
+        match self {
+            Reply::Refreshed { device, .. } => device.as_ref().err(),
+            Reply::Winked(result)
+            | Reply::Rebooted(result)
+            | Reply::Deleted { result, .. }
+            | Reply::Registered { result, .. } => result.as_ref().err(),
+            Reply::Codes { error, .. } => error.as_ref(),
+            Reply::Verified(result) => result.as_ref().err(),
+            Reply::Calculated(_)
+            | Reply::FirmwareUpdated(_)
+            | Reply::PinStatus(_)
+            | Reply::FidoInfo(_)
+            | Reply::PinSet { .. }
+            | Reply::Passkeys(_)
+            | Reply::PasskeyDeleted(_)
+            | Reply::FidoReset(_)
+            | Reply::SelfTest(_) => None,
+        }
+    }
+}
+
+/// Messages from the worker to the app.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The worker has started and takes commands through the sender
+    Ready(mpsc::Sender<Command>),
+    /// The worker finished an operation
+    Done(Operation, Reply),
+    /// A firmware update reached another stage
+    Firmware(Stage),
+    /// The device waits for the user to touch it before it finishes the running operation
+    Touch,
+    /// A FIDO2 reset reached another stage
+    Reset(reset::Stage),
+}
+
+/// Starts the worker and streams its events, for use with `Subscription::run_with`.
+/// The worker talks to simulated devices instead of real ones if `demo` is set.
+pub fn stream(demo: &bool) -> impl Stream<Item = Event> + use<> {
+    let demo = *demo;
+    iced_futures::stream::channel(8, move |mut emitter| async move {
+        let (command_sender, command_receiver) = mpsc::channel();
+        let (event_sender, mut event_receiver) = async_mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            let backend: Box<dyn Backend> = if demo {
+                Box::new(SimulatedBackend::default())
+            } else {
+                Box::new(Solo2Backend)
+            };
+            Worker::new(backend).run(command_receiver, event_sender)
+        });
+
+        _ = emitter.send(Event::Ready(command_sender)).await;
+        while let Some(event) = event_receiver.recv().await {
+            _ = emitter.send(event).await;
+        }
+    })
+}
+
+struct Worker {
+    /// Where devices come from
+    backend: Box<dyn Backend>,
+    /// The device we are conected to
+    device: Option<Box<dyn Device>>,
+    /// Which transport to talk to devices over
+    transport: TransportPreference,
+    /// Why the selected device isn't talked to over the preferred transport
+    transport_note: Option<String>,
+    /// The last PIN token and the UUID of the device it came from
+    pin_token: Option<(String, PinToken)>,
+}
+
+impl Worker {
+    fn new(backend: Box<dyn Backend>) -> Self {
+        Worker {
+            backend,
+            device: None,
+            transport: TransportPreference::default(),
+            transport_note: None,
+            pin_token: None,
+        }
+    }
+    /// Handles commands in order until the app goes away
+    fn run(
+        mut self,
+        commands: mpsc::Receiver<Command>,
+        events: async_mpsc::UnboundedSender<Event>,
+    ) {
+        for command in commands {
+            let operation = command.operation();
+            let mut reply = self.handle(command.clone(), &events);
+            if reply.error().is_some_and(Error::is_transport) {
+                // The connection went stale, so open the device again and retry once if that's safe
+                self.reconnect();
+                if command.is_idempotent() {
+                    reply = self.handle(command, &events);
+                }
+            }
+            if events.send(Event::Done(operation, reply)).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command, events: &async_mpsc::UnboundedSender<Event>) -> Reply {
+        match command {
+            Command::Refresh {
+                preferred,
+                clock,
+                transport,
+            } => {
+                self.transport = transport;
+                let uuids = self.select_device(&preferred);
+                Reply::Refreshed {
+                    pcsc_problem: uuids.is_empty().then(backend::pcsc_problem).flatten(),
+                    uuids,
+                    device: self.device_info(clock),
+                    recovery: self
+                        .backend
+                        .bootloaders()
+                        .iter()
+                        .map(|bootloader| bootloader.uuid())
+                        .collect(),
+                }
+            }
+            Command::Wink => Reply::Winked(self.device().and_then(|device| device.wink())),
+            Command::Reboot { bootloader } => Reply::Rebooted(self.reboot(bootloader)),
+            Command::LeaveBootloader(uuid) => Reply::Rebooted(self.leave_bootloader(&uuid)),
+            Command::Codes {
+                label,
+                count,
+                clock,
+            } => {
+                let mut codes = vec![];
+                let mut error = None;
+                for _ in 0..count {
+                    match self
+                        .device()
+                        .and_then(|device| device.calculate(&label, clock.now()))
+                    {
+                        Ok(code) => codes.push(code),
+                        Err(why) => {
+                            error = Some(why);
+                            break;
+                        }
+                    }
+                }
+                Reply::Codes {
+                    label,
+                    codes,
+                    error,
+                }
+            }
+            Command::Delete(label) => {
+                let result = self.device().and_then(|device| device.delete(&label));
+                Reply::Deleted { label, result }
+            }
+            Command::Register {
+                label,
+                secret,
+                hotp,
+            } => {
+                let credential = Credential {
+                    label,
+                    secret,
+                    hotp,
+                };
+                Reply::Registered {
+                    result: self
+                        .device()
+                        .and_then(|device| device.register(&credential)),
+                    label: credential.label,
+                    hotp,
+                }
+            }
+            Command::Verify { code, clock } => Reply::Verified(self.verify_code(&code, clock)),
+            Command::Calculate { label, challenge } => Reply::Calculated(match self.device() {
+                Ok(device) => device
+                    .calculate_challenge(&label, &challenge)
+                    .map_err(|why| format!("{why:#}")),
+                Err(why) => Err(why.to_string()),
+            }),
+            Command::UpdateFirmware { firmware, cancel } => {
+                Reply::FirmwareUpdated(self.update_firmware(&firmware, &cancel, |stage| {
+                    _ = events.send(Event::Firmware(stage));
+                }))
+            }
+            Command::RecoverFirmware { uuid, firmware } => {
+                Reply::FirmwareUpdated(self.recover_firmware(&uuid, &firmware, |stage| {
+                    _ = events.send(Event::Firmware(stage));
+                }))
+            }
+            Command::PinStatus => Reply::PinStatus(self.fido(client_pin::status)),
+            Command::FidoInfo => Reply::FidoInfo(self.fido(ctap2::get_info)),
+            Command::SetPin { current, pin } => Reply::PinSet {
+                changed: current.is_some(),
+                result: self.fido(|device| match &current {
+                    Some(current) => client_pin::change_pin(device, current, &pin),
+                    None => client_pin::set_pin(device, &pin),
+                }),
+            },
+            Command::ListPasskeys { pin } => Reply::Passkeys(self.list_passkeys(pin.as_deref())),
+            Command::DeletePasskey(id) => Reply::PasskeyDeleted(
+                self.pin_token()
+                    .and_then(|token| self.fido(|device| passkeys::delete(device, &token, &id))),
+            ),
+            Command::ResetFido { cancel } => Reply::FidoReset(self.reset_fido(&cancel, |stage| {
+                _ = events.send(Event::Reset(stage));
+            })),
+            Command::SelfTest { pin } => Reply::SelfTest(self.self_test(pin.as_deref(), || {
+                _ = events.send(Event::Touch);
+            })),
+        }
+    }
+
+    /// Reconnects to the device with UUID `preferred`, or whichever enumerated first,
+    /// and returns the UUIDs of all connected devices
+    fn select_device(&mut self, preferred: &str) -> Vec<String> {
+        // Get rid of the device to ensure connection to device is broken so it will be reset when the smart card state is refreshed, like when adding or deleting a key
+        self.device = None;
+        let mut devices = self.backend.devices();
+        let uuids: Vec<String> = devices.iter().map(|device| device.uuid()).collect();
+
+        let selected = uuids.iter().position(|uuid| uuid == preferred).unwrap_or(0);
+        if selected < devices.len() {
+            // Other devices are dropped here so they don't hold on to their connections
+            self.device = Some(devices.swap_remove(selected));
+        }
+        self.apply_transport();
+        uuids
+    }
+    /// Restarts the selected device, into its bootloader if `into_bootloader` is set, and waits
+    /// for it to show up again
+    fn reboot(&mut self, into_bootloader: bool) -> error::Result<()> {
+        let device = self.device()?;
+        let uuid = device.uuid();
+        let result = if into_bootloader {
+            device.boot_to_bootloader()
+        } else {
+            device.reboot()
+        };
+        // The device may restart before it answers, which looks like the connection breaking
+        match result {
+            Err(why) if !why.is_transport() => return Err(why),
+            _ => self.device = None,
+        }
+
+        let returned = if into_bootloader {
+            self.wait_for(|backend| {
+                backend
+                    .bootloaders()
+                    .into_iter()
+                    .find(|bootloader| bootloader.uuid() == uuid)
+                    .map(drop)
+            })
+        } else {
+            self.wait_for(|backend| {
+                backend
+                    .devices()
+                    .into_iter()
+                    .find(|device| device.uuid() == uuid)
+                    .map(drop)
+            })
+        };
+        returned.ok_or(Error::DidNotReturn("rebooting"))
+    }
+    /// Restarts a device in its bootloader into its firmware and waits for it to show up again
+    fn leave_bootloader(&mut self, uuid: &str) -> error::Result<()> {
+        let mut bootloader = self
+            .backend
+            .bootloaders()
+            .into_iter()
+            .find(|bootloader| bootloader.uuid() == uuid)
+            .ok_or(Error::NoDevice)?;
+        bootloader.reboot();
+        drop(bootloader);
+        self.wait_for(|backend| {
+            backend
+                .devices()
+                .into_iter()
+                .find(|device| device.uuid() == uuid)
+                .map(drop)
+        })
+        .ok_or(Error::DidNotReturn("leaving the bootloader"))
+    }
+    /// Reboots the selected device into its bootloader, flashes the firmware and waits for the
+    /// device to come back, returning the version it reports then
+    fn update_firmware(
+        &mut self,
+        firmware: &[u8],
+        cancel: &AtomicBool,
+        report: impl Fn(Stage),
+    ) -> Result<String, String> {
+        report(Stage::Rebooting);
+        let device = self.device().map_err(|why| why.to_string())?;
+        let uuid = device.uuid();
+        if cancel.load(Ordering::Relaxed) {
+            return Err("Firmware update cancelled.".to_string());
+        }
+        // The device may restart before it answers, which looks like the connection breaking
+        match device.boot_to_bootloader() {
+            Err(why) if !why.is_transport() => return Err(why.to_string()),
+            _ => self.device = None,
+        }
+
+        report(Stage::WaitingForBootloader);
+        let mut bootloader = self
+            .wait_for(|backend| {
+                backend
+                    .bootloaders()
+                    .into_iter()
+                    .find(|bootloader| bootloader.uuid() == uuid)
+            })
+            .ok_or("The key didn't show up in bootloader mode.")?;
+        // Last chance to back out with the old firmware still in place
+        if cancel.load(Ordering::Relaxed) {
+            bootloader.reboot();
+            return Err(
+                "Firmware update cancelled. The key restarts with its old firmware.".to_string(),
+            );
+        }
+        self.flash(bootloader, &uuid, firmware, report)
+    }
+    /// Installs firmware on a device that is stuck in its bootloader, like after an interrupted update
+    fn recover_firmware(
+        &mut self,
+        uuid: &str,
+        firmware: &[u8],
+        report: impl Fn(Stage),
+    ) -> Result<String, String> {
+        let bootloader = self
+            .backend
+            .bootloaders()
+            .into_iter()
+            .find(|bootloader| bootloader.uuid() == uuid)
+            .ok_or("The key that needed recovery is gone.")?;
+        self.flash(bootloader, uuid, firmware, report)
+    }
+    /// Writes firmware with a bootloader, then restarts it and waits for the device to come back
+    /// running the new firmware, returning the version it reports
+    fn flash(
+        &mut self,
+        mut bootloader: Box<dyn Bootloader>,
+        uuid: &str,
+        firmware: &[u8],
+        report: impl Fn(Stage),
+    ) -> Result<String, String> {
+        report(Stage::Flashing(0.0));
+        bootloader
+            .flash(firmware, &|written| {
+                report(Stage::Flashing(written as f32 / firmware.len() as f32));
+            })
+            .map_err(|why| format!("Could not write the firmware: {why:#}"))?;
+        bootloader.reboot();
+
+        report(Stage::Restarting);
+        self.device = self.wait_for(|backend| {
+            backend
+                .devices()
+                .into_iter()
+                .find(|device| device.uuid() == uuid)
+        });
+        self.apply_transport();
+        match self.device.as_ref() {
+            Some(device) => Ok(device.version()),
+            None => Err("The firmware was written, but the key didn't come back.".to_string()),
+        }
+    }
+    /// Looks for something that shows up after a reboot until it's found or it takes too long
+    fn wait_for<T>(&mut self, mut find: impl FnMut(&mut dyn Backend) -> Option<T>) -> Option<T> {
+        let start = Instant::now();
+        while start.elapsed() < REBOOT_TIMEOUT {
+            if let Some(found) = find(self.backend.as_mut()) {
+                return Some(found);
+            }
+            std::thread::sleep(REBOOT_POLL_INTERVAL);
+        }
+        None
+    }
+    /// Waits for the user to unplug the selected device and plug it back in, then resets FIDO2 on
+    /// it straight away, since keys only accept a reset in the first seconds after power up
+    fn reset_fido(
+        &mut self,
+        cancel: &AtomicBool,
+        report: impl Fn(reset::Stage),
+    ) -> Result<(), String> {
+        let uuid = self.device().map_err(|why| why.to_string())?.uuid();
+        // The reset deletes the PIN, and the key forgets the token when unplugged anyway
+        self.pin_token = None;
+        self.device = None;
+        let cancelled = || Err("FIDO2 reset cancelled. Nothing was deleted.".to_string());
+
+        report(reset::Stage::Unplug);
+        let start = Instant::now();
+        while self
+            .backend
+            .devices()
+            .iter()
+            .any(|device| device.uuid() == uuid)
+        {
+            if cancel.load(Ordering::Relaxed) {
+                return cancelled();
+            }
+            if start.elapsed() > REPLUG_TIMEOUT {
+                return Err("The key wasn't unplugged, so nothing was reset.".to_string());
+            }
+            std::thread::sleep(REBOOT_POLL_INTERVAL);
+        }
+
+        report(reset::Stage::Replug);
+        let start = Instant::now();
+        let mut device = loop {
+            if let Some(device) = self
+                .backend
+                .devices()
+                .into_iter()
+                .find(|device| device.uuid() == uuid)
+            {
+                break device;
+            }
+            if cancel.load(Ordering::Relaxed) {
+                return cancelled();
+            }
+            if start.elapsed() > REPLUG_TIMEOUT {
+                return Err("The key wasn't plugged back in, so nothing was reset.".to_string());
+            }
+            std::thread::sleep(REBOOT_POLL_INTERVAL);
+        };
+        let result = ctap2::reset(device.as_mut(), &|| report(reset::Stage::Touch))
+            .map_err(|why| reset::explain(&why));
+        self.device = Some(device);
+        self.apply_transport();
+        result
+    }
+    /// Reopens the selected device by UUID, or forgets it if it's gone
+    fn reconnect(&mut self) {
+        // Drop the stale connection first so it doesn't get in the way of the new one
+        let Some(uuid) = self.device.take().map(|device| device.uuid()) else {
+            return;
+        };
+        self.device = self
+            .backend
+            .devices()
+            .into_iter()
+            .find(|device| device.uuid() == uuid);
+        self.apply_transport();
+    }
+    /// Switches the selected device to the preferred transport, noting why if it can't be
+    fn apply_transport(&mut self) {
+        self.transport_note = self
+            .device
+            .as_mut()
+            .and_then(|device| backend::choose_transport(device.as_mut(), self.transport));
+    }
+    /// Lists the passkeys on the selected device, getting a new PIN token first if `pin` is given
+    fn list_passkeys(&mut self, pin: Option<&str>) -> Result<Passkeys, String> {
+        if let Some(pin) = pin {
+            self.enter_pin(pin)?;
+        }
+        let token = self.pin_token()?;
+        self.fido(|device| passkeys::list(device, &token))
+    }
+    /// Runs the self-test on the selected device, getting a new PIN token first if `pin` is given.
+    /// Without one, the test goes ahead without a token if there's none, for keys without a PIN.
+    fn self_test(
+        &mut self,
+        pin: Option<&str>,
+        touch: impl Fn(),
+    ) -> Result<self_test::Report, String> {
+        if let Some(pin) = pin {
+            self.enter_pin(pin)?;
+        }
+        let token = self.pin_token().ok();
+        self.fido(|device| self_test::run(device, token.as_ref(), &touch))
+    }
+    /// Exchanges `pin` for a PIN token for the selected device, replacing the one from before
+    fn enter_pin(&mut self, pin: &str) -> Result<(), String> {
+        let token = self.fido(|device| client_pin::pin_token(device, pin))?;
+        let uuid = self.device().map_err(|why| why.to_string())?.uuid();
+        self.pin_token = Some((uuid, token));
+        Ok(())
+    }
+    /// The PIN token of the selected device, if the PIN was entered for it
+    fn pin_token(&mut self) -> Result<PinToken, String> {
+        let uuid = self.device().map_err(|why| why.to_string())?.uuid();
+        match &self.pin_token {
+            Some((token_uuid, token)) if *token_uuid == uuid => Ok(token.clone()),
+            _ => Err("Enter the PIN first.".to_string()),
+        }
+    }
+    /// Runs a FIDO2 operation on the selected device, with errors as text for the interface
+    fn fido<T>(
+        &mut self,
+        operation: impl FnOnce(&mut dyn Device) -> anyhow::Result<T>,
+    ) -> Result<T, String> {
+        let device = self.device().map_err(|why| why.to_string())?;
+        operation(device).map_err(|why| format!("{why:#}"))
+    }
+    /// The selected device, or an error if there isn't one
+    fn device(&mut self) -> error::Result<&mut dyn Device> {
+        match self.device.as_mut() {
+            Some(device) => Ok(device.as_mut()),
+            None => Err(Error::NoDevice),
+        }
+    }
+    /// Gets everything the interface shows about the selected device
+    fn device_info(&mut self, clock: Clock) -> error::Result<Option<DeviceInfo>> {
+        let Some(device) = self.device.as_mut() else {
+            return Ok(None);
+        };
+        let now = clock.now();
+        let mut totp_list: Vec<CredentialCode> = vec![];
+        for credential in device.list()? {
+            // Calculating an HOTP code would use it up
+            let code = if credential.hotp {
+                Ok("".to_string())
+            } else {
+                // One credential failing, like one that needs a touch, shouldn't hide the others,
+                // but a broken connection should still be reconnected
+                match device.calculate(&credential.label, now) {
+                    Ok(code) => Ok(code),
+                    Err(why) if why.is_transport() => return Err(why),
+                    Err(why) => Err(why.to_string()),
+                }
+            };
+            totp_list.push(CredentialCode {
+                label: credential.label,
+                hotp: credential.hotp,
+                code,
+            });
+        }
+
+        Ok(Some(DeviceInfo {
+            uuid: device.uuid(),
+            version: device.version(),
+            locked: device.locked()?,
+            transport: device.transport().to_string(),
+            transport_note: self.transport_note.clone(),
+            transports: device
+                .transports()
+                .iter()
+                .map(Transport::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+            connection: device.connection(),
+            applets: backend::applets(device.as_mut()),
+            totp_list,
+        }))
+    }
+    /// Asks the device for the codes of every credential in the previous, current and next window
+    /// and returns the labels and window offsets (-1, 0 or 1) which produced the given code.
+    /// Credentials that need a touch are skipped, while any other error, like the connection
+    /// breaking, fails the whole check so it isn't mistaken for the code not matching.
+    /// HOTP credentials are skipped too, since calculating their codes would move their counters.
+    fn verify_code(&mut self, code: &str, clock: Clock) -> error::Result<Vec<(String, i64)>> {
+        let device = self.device()?;
+        let credentials = device.list()?;
+        let now = clock.now();
+        let mut matches: Vec<(String, i64)> = vec![];
+
+        for label in credentials
+            .into_iter()
+            .filter(|credential| !credential.hotp)
+            .map(|credential| credential.label)
+        {
+            for window in -1i64..=1 {
+                let timestamp = now.saturating_add_signed(window * TOTP_PERIOD as i64);
+                let device_code = match device.calculate(&label, timestamp) {
+                    Ok(device_code) => device_code,
+                    Err(why) if why.is_touch_required() => break,
+                    Err(why) => return Err(why),
+                };
+                if device_code == code {
+                    matches.push((label.clone(), window));
+                }
+            }
+        }
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::simulated::TEST_SECRET;
+
+    /// UUID of the second simulated device
+    const BACKUP: &str = "5eed0000000000000000000000000002";
+    /// One second before the second TOTP window ends, where RFC 6238 has a test vector
+    pub(crate) const CLOCK: Clock = Clock::Fixed(59);
+
+    /// A worker on its own thread, driven through the same channels the app uses.
+    pub(crate) struct Harness {
+        pub(crate) commands: mpsc::Sender<Command>,
+        pub(crate) events: async_mpsc::UnboundedReceiver<Event>,
+    }
+
+    impl Harness {
+        /// A worker talking to the simulated devices
+        pub(crate) fn start() -> Self {
+            Harness::with_backend(Box::new(SimulatedBackend::default()))
+        }
+        pub(crate) fn with_backend(backend: Box<dyn Backend>) -> Self {
+            let (commands, command_receiver) = mpsc::channel();
+            let (event_sender, events) = async_mpsc::unbounded_channel();
+            std::thread::spawn(move || Worker::new(backend).run(command_receiver, event_sender));
+            Harness { commands, events }
+        }
+        /// Sends a command and waits for the worker to finish it
+        pub(crate) fn request(&mut self, command: Command) -> Reply {
+            let expected = command.operation();
+            self.commands.send(command).unwrap();
+            loop {
+                match self.events.blocking_recv() {
+                    Some(Event::Done(operation, reply)) => {
+                        assert_eq!(operation, expected);
+                        return reply;
+                    }
+                    Some(_) => {}
+                    None => panic!("The worker stopped"),
+                }
+            }
+        }
+        /// Looks for devices and selects `preferred`, returning the UUIDs found and the device
+        /// that ended up selected, if any
+        pub(crate) fn select(&mut self, preferred: &str) -> (Vec<String>, Option<DeviceInfo>) {
+            let reply = self.request(Command::Refresh {
+                preferred: preferred.to_string(),
+                clock: CLOCK,
+                transport: TransportPreference::Auto,
+            });
+            let Reply::Refreshed { uuids, device, .. } = reply else {
+                panic!("Unexpected reply {reply:?}");
+            };
+            (uuids, device.unwrap())
+        }
+        fn refresh(&mut self, preferred: &str) -> DeviceInfo {
+            let (uuids, device) = self.select(preferred);
+            assert_eq!(uuids.len(), 2);
+            device.expect("A device is selected")
+        }
+    }
+
+    pub(crate) fn labels(info: &DeviceInfo) -> Vec<&str> {
+        info.totp_list
+            .iter()
+            .map(|credential| credential.label.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn refresh_lists_credentials_with_their_codes() {
+        let mut worker = Harness::start();
+        let info = worker.refresh(BACKUP);
+        assert_eq!(info.uuid, BACKUP);
+        assert_eq!(info.version, "1.0.4");
+        assert_eq!(labels(&info), ["GitHub", "Mail", "VPN"]);
+        for credential in info.totp_list {
+            assert!(!credential.hotp);
+            assert_eq!(credential.code.as_deref(), Ok("287082"));
+        }
+    }
+
+    #[test]
+    fn registers_calculates_and_deletes_credentials() {
+        let mut worker = Harness::start();
+        worker.refresh("");
+        let reply = worker.request(Command::Register {
+            label: "Counter".to_string(),
+            secret: TEST_SECRET.to_string(),
+            hotp: true,
+        });
+        assert!(matches!(
+            reply,
+            Reply::Registered {
+                hotp: true,
+                result: Ok(()),
+                ..
+            }
+        ));
+
+        // Listing an HOTP credential mustn't use up its codes
+        let info = worker.refresh("");
+        let counter = info.totp_list.last().unwrap();
+        assert_eq!(counter.label, "Counter");
+        assert!(counter.hotp);
+        assert_eq!(counter.code.as_deref(), Ok(""));
+
+        let reply = worker.request(Command::Codes {
+            label: "Counter".to_string(),
+            count: 2,
+            clock: CLOCK,
+        });
+        let Reply::Codes { codes, error, .. } = reply else {
+            panic!("Unexpected reply {reply:?}");
+        };
+        assert_eq!(codes, ["755224", "287082"]);
+        assert!(error.is_none());
+
+        let reply = worker.request(Command::Delete("Counter".to_string()));
+        assert!(matches!(reply, Reply::Deleted { result: Ok(()), .. }));
+        assert_eq!(labels(&worker.refresh("")), ["GitHub", "Mail", "VPN"]);
+    }
+
+    #[test]
+    fn verifies_codes_of_neighbouring_windows() {
+        let mut worker = Harness::start();
+        worker.refresh("");
+        // The code of the window before 59 seconds
+        let reply = worker.request(Command::Verify {
+            code: "755224".to_string(),
+            clock: CLOCK,
+        });
+        let Reply::Verified(Ok(matches)) = reply else {
+            panic!("Unexpected reply {reply:?}");
+        };
+        assert_eq!(
+            matches,
+            [
+                ("GitHub".to_string(), -1),
+                ("Mail".to_string(), -1),
+                ("VPN".to_string(), -1)
+            ]
+        );
+    }
+
+    #[test]
+    fn fails_without_a_device() {
+        let mut worker = Harness::start();
+        let reply = worker.request(Command::Wink);
+        assert!(matches!(reply, Reply::Winked(Err(Error::NoDevice))));
+        let reply = worker.request(Command::Delete("GitHub".to_string()));
+        assert!(matches!(
+            reply,
+            Reply::Deleted {
+                result: Err(Error::NoDevice),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn reports_device_errors() {
+        let mut worker = Harness::start();
+        worker.refresh("");
+
+        let reply = worker.request(Command::Register {
+            label: "Broken".to_string(),
+            secret: "not base32!".to_string(),
+            hotp: false,
+        });
+        assert!(matches!(
+            reply,
+            Reply::Registered {
+                result: Err(Error::InvalidCredential(_)),
+                ..
+            }
+        ));
+
+        let reply = worker.request(Command::Delete("Missing".to_string()));
+        assert!(matches!(
+            reply,
+            Reply::Deleted {
+                result: Err(Error::Device(..)),
+                ..
+            }
+        ));
+
+        let reply = worker.request(Command::Codes {
+            label: "Missing".to_string(),
+            count: 3,
+            clock: CLOCK,
+        });
+        let Reply::Codes { codes, error, .. } = reply else {
+            panic!("Unexpected reply {reply:?}");
+        };
+        assert!(codes.is_empty());
+        assert!(matches!(error, Some(Error::Device(..))));
+
+        let reply = worker.request(Command::Calculate {
+            label: "GitHub".to_string(),
+            challenge: vec![0; 65],
+        });
+        assert!(matches!(reply, Reply::Calculated(Err(_))));
+        // Nothing was registered or deleted by the failed commands
+        assert_eq!(labels(&worker.refresh("")), ["GitHub", "Mail", "VPN"]);
+    }
+
+    #[test]
+    fn calculates_challenges() {
+        let mut worker = Harness::start();
+        worker.refresh("");
+        // A TOTP challenge is the window number, so this matches the code at 59 seconds
+        let reply = worker.request(Command::Calculate {
+            label: "GitHub".to_string(),
+            challenge: 1u64.to_be_bytes().to_vec(),
+        });
+        let Reply::Calculated(Ok(calculation)) = reply else {
+            panic!("Unexpected reply {reply:?}");
+        };
+        assert_eq!(calculation.code(), "287082");
+    }
+}